@@ -1,15 +1,41 @@
+use anyhow::ensure;
 use anyhow::Error;
 use anyhow::Result;
+use base64::Engine;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use roaring::RoaringTreemap;
 use serde::Deserialize;
 use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
 use std::io;
 use std::io::BufRead;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
+use std::mem;
 use std::sync;
+use std::sync::atomic;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
 use std::thread;
+use std::thread::JoinHandle;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use strum::IntoStaticStr;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,11 +61,111 @@ impl Msg {
         }
     }
 
+    // for a msg made by `into_resp`, fill in the reply payload and send it back
+    fn reply(mut self, pl: Pl, stdout: &mut impl Write) -> Result<()> {
+        self.body.pl = pl;
+        self.send(stdout)
+    }
+
     fn send(self, stdout: &mut impl Write) -> Result<()> {
-        serde_json::to_writer(&mut *stdout, &self)?;
-        stdout.write_all(b"\n")?;
-        Ok(())
+        // clients and services always get theirs, a lost reply to them fails the test rather than a retry
+        if cfg!(feature = "lossy") && self.dst.starts_with('n') && lost() {
+            return Ok(());
+        }
+        write_out(&self, stdout)
+    }
+
+    // u32 big endian length then that many bytes of json, `None` once the input is closed
+    fn read_frame(input: &mut impl Read) -> Result<Option<Msg>> {
+        let mut len = [0; 4];
+        match input.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            x => x?,
+        }
+        // checked before allocating, a garbled length would otherwise ask for up to 4GiB
+        let len = u32::from_be_bytes(len) as usize;
+        ensure!(
+            len <= MAX_FRAME,
+            "frame of {len} bytes, at most {MAX_FRAME}"
+        );
+        let mut body = vec![0; len];
+        input.read_exact(&mut body)?;
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+}
+
+// `framed`, largest msg read, far above the biggest gossip or poll_ok we'd send
+const MAX_FRAME: usize = 16 << 20;
+
+// `lossy`, percent of the msgs to other nodes that `send` drops, to see retries and
+// anti-entropy at work without partitioning anything
+const LOSS_PERCENT: u64 = 50;
+// mixed with the node position at init, so every node drops its own pattern and the same one on every run
+const LOSS_SEED: u64 = 0x1055;
+static LOSS_RNG: AtomicU64 = AtomicU64::new(LOSS_SEED);
+
+fn loss_seed(node: u64) -> u64 {
+    // xorshift never leaves 0
+    (LOSS_SEED ^ node.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15)).max(1)
+}
+
+// tests drive this with an rng of their own instead of `LOSS_RNG`
+fn drops(rng: &mut u64, percent: u64) -> bool {
+    xorshift(rng) % 100 < percent
+}
+
+fn lost() -> bool {
+    let mut x = LOSS_RNG.load(atomic::Ordering::Relaxed);
+    let lost = drops(&mut x, LOSS_PERCENT);
+    LOSS_RNG.store(x, atomic::Ordering::Relaxed);
+    lost
+}
+
+// anything shaped like a `Msg`, one json per line or one frame with `framed`
+fn write_out(msg: &impl Serialize, stdout: &mut impl Write) -> Result<()> {
+    if cfg!(feature = "framed") {
+        let body = serde_json::to_vec(msg)?;
+        stdout.write_all(&u32::try_from(body.len())?.to_be_bytes())?;
+        stdout.write_all(&body)?;
+        return Ok(());
     }
+    serde_json::to_writer(&mut *stdout, msg)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+// `read_ok` serialized straight from the broadcast set, `Pl::ReadOk` would need a clone of it
+#[derive(Serialize)]
+struct ReadOkRef<'a> {
+    src: &'a str,
+    #[serde(rename = "dest")]
+    dst: &'a str,
+    body: ReadOkBody<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOkBody<'a> {
+    #[serde(
+        rename = "messages",
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "msgs_as_seq"
+    )]
+    msgs: Option<&'a RoaringTreemap>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merged_at: Option<u64>,
+    msg_id: Option<usize>,
+    in_reply_to: Option<usize>,
+}
+
+// roaring's own serde is its binary format, the checker wants a plain list
+fn msgs_as_seq<S: serde::Serializer>(
+    msgs: &Option<&RoaringTreemap>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(msgs.iter().flat_map(|x| x.iter()))
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,19 +176,32 @@ struct Body {
     in_reply_to: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// `IntoStaticStr` gives the same name as the `type` tag, for counting msgs by type
+#[derive(Serialize, Deserialize, Debug, Clone, IntoStaticStr)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
 enum Pl {
     Txn {
         txn: Vec<TxnOp>,
+        // ask for what each write overwrote, see `prior` in txn_ok
+        #[serde(default)]
+        prior: bool,
     },
     TxnOk {
         txn: Vec<TxnOp>,
+        // lined up with `txn`, the value a write replaced, null for reads and fresh keys
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        prior: Vec<Option<Val>>,
     },
     BroadcastTxn {
+        epoch: usize,
         txns: Vec<SeqTxn>,
     },
+    // the sender stops resending its part of `epoch` to us
+    BroadcastTxnOk {
+        epoch: usize,
+    },
     Error {
         code: usize,
         text: String,
@@ -85,8 +224,31 @@ enum Pl {
     Broadcast {
         #[serde(rename = "message")]
         msg: usize,
+        // with `trace`, record the path the msg takes through the nodes
+        #[serde(default)]
+        trace: bool,
     },
     BroadcastOk,
+    Remove {
+        #[serde(rename = "message")]
+        msg: usize,
+    },
+    RemoveOk,
+    Stats,
+    StatsOk {
+        metrics: Box<Metrics>,
+    },
+    // the client wants a `notify` for every new broadcast instead of polling with `read`
+    Subscribe,
+    SubscribeOk,
+    Notify {
+        #[serde(rename = "message")]
+        msg: usize,
+    },
+    // full or-set state, merged on receipt
+    GossipSet {
+        set: OrSet,
+    },
     // read is used to read from the node, but it's also used in lin-kv!
     // by default it's just read and for lin-kv we have fields
     Read {
@@ -94,12 +256,25 @@ enum Pl {
         key: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         msg_id: Option<usize>,
+        // counter to read, the default one when missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        // g-counter, hold the read until gossip brought the total up to this
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at_least: Option<usize>,
     },
     ReadOk {
         #[serde(rename = "messages", skip_serializing_if = "Option::is_none")]
         msgs: Option<HashSet<usize>>,
+        // a counter, or with `kv` a register that can be negative
         #[serde(skip_serializing_if = "Option::is_none")]
-        value: Option<usize>,
+        value: Option<i64>,
+        // set when `at_least` wasn't reached in time and `value` is below it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        behind: Option<bool>,
+        // `read-freshness`, unix ms of the last gossip that raised this counter, none before any did
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        merged_at: Option<u64>,
     },
     Topology {
         topology: HashMap<String, Vec<String>>,
@@ -108,30 +283,93 @@ enum Pl {
     Gossip {
         #[serde(rename = "messages")]
         msgs: HashSet<usize>,
+        // base64 deflated `messages`, set instead of them when the sender compresses
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        deflated: Option<String>,
+        // the sender counters riding along, only with `piggyback`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cntr: Option<HashMap<String, usize>>,
+        // (msg, nodes it went through so far) for the traced ones in `messages`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        traces: Vec<(usize, Vec<String>)>,
     },
     GossipOk {
         id: usize,
     },
     GossipCntr {
-        cntr: usize,
+        cntr: HashMap<String, usize>,
+    },
+    // anti-entropy with `merkle`, (lo, hi, hash of the sender msgs in lo..hi) to compare,
+    // and the sender msgs of the ranges that were small enough to just send
+    Digest {
+        ranges: Vec<(usize, usize, u64)>,
+        #[serde(rename = "messages", default, skip_serializing_if = "Vec::is_empty")]
+        msgs: Vec<usize>,
+    },
+    // to the origin of a traced msg, the path it took to reach the sender
+    TracePath {
+        #[serde(rename = "message")]
+        msg: usize,
+        path: Vec<String>,
+    },
+    Trace {
+        #[serde(rename = "message")]
+        msg: usize,
+    },
+    TraceOk {
+        paths: Vec<Vec<String>>,
+    },
+    // sent to neighbours after init so they forget what they think we have seen,
+    // also tells them which wire extensions we can read, none when left out
+    Rejoin {
+        #[serde(default)]
+        extensions: Vec<String>,
+    },
+    Heartbeat {
+        // from the leader, its log length per key so replicas know how far behind they are
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        heads: HashMap<String, usize>,
     },
     Add {
         delta: usize,
+        #[serde(default)]
+        name: Option<String>,
     },
     AddOk,
+    Cas {
+        key: String,
+        from: usize,
+        to: usize,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    // seq-kv write, here only to move our own key before a read
+    Write {
+        key: String,
+        value: usize,
+    },
+    WriteOk,
     Send {
         key: String,
         msg: usize,
+        // optional idempotency key, a send with a token already used for `key` is not appended again
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
     },
     SendMany {
         key: String,
         msgs: Vec<usize>,
     },
+    // only asked for, through a msg_id on `send_many`, with `write-quorum`
+    SendManyOk,
     SendOk {
         offset: usize,
     },
     Poll {
         offsets: HashMap<String, usize>,
+        // a replica more than this many msgs behind the leader on a key passes the poll on to it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_lag: Option<usize>,
     },
     PollOk {
         msgs: HashMap<String, Vec<(usize, usize)>>,
@@ -146,21 +384,191 @@ enum Pl {
     ListCommittedOffsetsOk {
         offsets: HashMap<String, usize>,
     },
+    // any type not above, so a msg we don't know doesn't fail the parse and take the reader down,
+    // its fields are dropped, see `UNKNOWN_POLICY`
+    #[serde(other)]
+    Unknown,
+}
+
+// what a `stats` request gets back, only sizes and counters so it stays cheap to take
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Metrics {
+    // kafka leader or replica
+    role: String,
+    msgs_in: usize,
+    // by their `type`, what we don't handle counts as `unknown`
+    msgs_by_type: HashMap<String, usize>,
+    messages: usize,
+    // msgs this node knows each neighbour has
+    seen: HashMap<String, usize>,
+    gossip_pending: usize,
+    kv_pending: usize,
+    relays: usize,
+    // txns of unapplied or unacked epochs, clients get 11 past `MAX_BUFFERED_TXNS`
+    buffered_txns: usize,
+    store_version: usize,
+    // msgs of a type we don't handle
+    unknown: usize,
+    // msgs read but not yet taken by the loop, this one excluded
+    queued: usize,
+    // mesh gossip rounds skipped past `GOSSIP_SHED_QUEUED`
+    gossip_shed: usize,
+    // forwarded requests sent to the leader again for lack of an answer
+    relay_retries: usize,
 }
 
+// constructed only by the feature gated timers
+#[allow(dead_code)]
+#[derive(Clone)]
 enum Task {
     CentralGossip,
     MeshGossip,
     GossipCntr,
     SwitchPhase,
+    Heartbeat,
+    RetryRelays,
+    AntiEntropy,
+    Flush,
+    // a reply held back off the loop, sent as is
+    Reply(Box<Msg>),
+    // an add whose cas lost, read again once its backoff is over
+    RetryAdd {
+        client: Box<Msg>,
+        key: String,
+        delta: usize,
+        tries: u32,
+    },
 }
 
+// nearly every event is `Ext`, boxing it would just be one more allocation per msg
+#[allow(dead_code, clippy::large_enum_variant)]
 enum Evt {
     Ext(Msg),
     Int(Task),
+    // stdin closed, the test is over
+    Eof,
+}
+
+// client requests waiting on seq-kv, keyed by the msg_id of our request to it
+enum KvOp {
+    // the write to our sync key, the read of `key` goes out once it's acked
+    Sync {
+        client: Msg,
+        key: String,
+    },
+    Read {
+        client: Msg,
+    },
+    // read then cas from the read value, retried from the read when the cas loses
+    Add {
+        client: Msg,
+        key: String,
+        delta: usize,
+        // cas lost so far, sets the backoff before the next read
+        tries: u32,
+    },
+}
+
+// a lost cas waits anywhere below this before reading again, doubled per loss up to `CAS_BACKOFF_MAX`,
+// random so the adds that lost together don't all come back together
+const CAS_BACKOFF: Duration = Duration::from_millis(5);
+const CAS_BACKOFF_MAX: Duration = Duration::from_millis(200);
+
+// how long `echo_ok` is held back with `slow-echo`, to see how the rest copes with slow handlers
+#[cfg(feature = "slow-echo")]
+const ECHO_DELAY: Duration = Duration::from_millis(100);
+
+// events queued for the loop, per channel
+const EVT_CAPACITY: usize = 1024;
+// past this many msgs waiting for the loop, new client requests get error 11 so they back off
+const OVERLOAD_QUEUED: usize = EVT_CAPACITY * 3 / 4;
+// past this many, every other mesh gossip round is skipped so gossip and its acks don't add to it
+const GOSSIP_SHED_QUEUED: usize = EVT_CAPACITY / 2;
+// how long the loop waits on client msgs before looking at timer tasks again
+const INT_POLL: Duration = Duration::from_millis(5);
+
+// when the buffered replies get written out, whatever the policy they also are once the loop is idle
+enum FlushPolicy {
+    // before waiting on every next event, lowest latency
+    PerEvent,
+    // once no more input is queued, one write for a burst
+    Drained,
+    // every `n` events handled
+    EveryN { n: usize },
+    // on a timer task, so a steady stream still gets flushed
+    Interval { every: Duration },
+}
+
+const FLUSH_POLICY: FlushPolicy = if cfg!(feature = "batch-flush") {
+    FlushPolicy::Drained
+} else if cfg!(feature = "flush-every-n") {
+    FlushPolicy::EveryN { n: 64 }
+} else if cfg!(feature = "flush-interval") {
+    FlushPolicy::Interval {
+        every: Duration::from_millis(10),
+    }
+} else {
+    FlushPolicy::PerEvent
+};
+
+// what to do with a msg of a type we don't handle
+enum UnknownPolicy {
+    // error 10 back to the sender, maelstrom's not supported
+    Reject,
+    // only logged, for senders that don't expect an answer to it
+    Ignore,
+}
+
+const UNKNOWN_POLICY: UnknownPolicy = if cfg!(feature = "ignore-unknown") {
+    UnknownPolicy::Ignore
+} else {
+    UnknownPolicy::Reject
+};
+
+const SEQ_KV: &str = "seq-kv";
+// maelstrom services, addressable like nodes but never gossip peers or leaders
+const SERVICE_NODES: &[&str] = &["lin-kv", SEQ_KV, "lww-kv", "lin-tso"];
+// counter used when `add`/`read` don't name one, also its seq-kv key
+const CNTR_KEY: &str = "cntr";
+
+// register values come as json numbers, or as strings from clients that can't
+// put more than 2^53 in a number, either way they are kept as an i64 and go back out
+// in the form they came in
+#[derive(Debug, Clone, Copy)]
+struct Val {
+    n: i64,
+    quoted: bool,
+}
+
+impl Serialize for Val {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.quoted {
+            serializer.collect_str(&self.n)
+        } else {
+            serializer.serialize_i64(self.n)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Val {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Num(i64),
+            Str(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Num(n) => Ok(Val { n, quoted: false }),
+            Raw::Str(x) => x
+                .parse()
+                .map(|n| Val { n, quoted: true })
+                .map_err(serde::de::Error::custom),
+        }
+    }
 }
 
-type TxnOp = (char, usize, Option<usize>);
+type TxnOp = (char, usize, Option<Val>);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct SeqTxn {
@@ -168,6 +576,29 @@ struct SeqTxn {
     txn: Vec<TxnOp>,
 }
 
+// txns held in `Broadcaster` before we start answering 11 so clients back off, a silent neighbour
+// keeps every epoch after its missing part buffered so this is what bounds them
+const MAX_BUFFERED_TXNS: usize = 10_000;
+// ops in one txn, maelstrom sends a handful, anything near this is a broken client
+const MAX_TXN_OPS: usize = 1_000;
+
+// 11 backs the client off until the buffered epochs drain, 14 is abort, nothing of it was applied
+fn txn_error(buffered: usize, ops: usize) -> Option<Pl> {
+    if buffered >= MAX_BUFFERED_TXNS {
+        Some(Pl::Error {
+            code: 11,
+            text: format!("{buffered} txns buffered, at most {MAX_BUFFERED_TXNS}"),
+        })
+    } else if ops > MAX_TXN_OPS {
+        Some(Pl::Error {
+            code: 14,
+            text: format!("txn of {ops} ops, at most {MAX_TXN_OPS}"),
+        })
+    } else {
+        None
+    }
+}
+
 struct State {
     txn_id: usize,
     cur_epoch: usize,
@@ -189,6 +620,7 @@ impl State {
         id
     }
 
+    // receiving, then sending under the next epoch, then receiving again, the epoch never goes back
     fn switch_phase(&mut self) {
         self.receiving = !self.receiving;
         eprintln!("receiving = {}", self.receiving);
@@ -201,6 +633,12 @@ impl State {
 struct Broadcaster {
     broadcast_nodes: HashMap<usize, HashMap<String, Vec<SeqTxn>>>,
     neighborhood: Vec<String>,
+    // next epoch whose txns from the others get applied, the ones before it are gone from `broadcast_nodes`
+    applied: usize,
+    // our part of each closed epoch and the neighbours that didn't ack it yet, resent until they do
+    unacked: BTreeMap<usize, (Vec<SeqTxn>, HashSet<String>)>,
+    // txns in `broadcast_nodes` and `unacked` together
+    buffered: usize,
 }
 
 impl Broadcaster {
@@ -208,6 +646,9 @@ impl Broadcaster {
         Self {
             broadcast_nodes: HashMap::new(),
             neighborhood: Vec::new(),
+            applied: 0,
+            unacked: BTreeMap::new(),
+            buffered: 0,
         }
     }
 
@@ -215,19 +656,32 @@ impl Broadcaster {
         self.neighborhood = from_ids;
     }
 
+    // the same node can send the same epoch more than once, merge by seq so it stays idempotent
     fn push(&mut self, timestamp: usize, nodeid: String, txns: Vec<SeqTxn>) {
-        self.broadcast_nodes
+        // a repeat of an epoch already applied, it had every part so this one was in it
+        if timestamp < self.applied {
+            return;
+        }
+        let buffered = self
+            .broadcast_nodes
             .entry(timestamp)
             .or_default()
-            .insert(nodeid, txns);
+            .entry(nodeid)
+            .or_default();
+        for txn in txns {
+            if !buffered.iter().any(|x| x.seq == txn.seq) {
+                buffered.push(txn);
+                self.buffered += 1;
+            }
+        }
     }
 
+    // every neighbour sent its part, a push from outside the neighbourhood doesn't stand in for one
     fn has_all(&self, epoch: usize) -> bool {
         let epoch_broadcasts = self.broadcast_nodes.get(&epoch);
-        if epoch_broadcasts.is_none() {
-            return self.neighborhood.is_empty();
-        }
-        epoch_broadcasts.unwrap().len() == self.neighborhood.len()
+        self.neighborhood
+            .iter()
+            .all(|x| epoch_broadcasts.is_some_and(|y| y.contains_key(x)))
     }
 
     fn get_all(&self, epoch: usize) -> Vec<SeqTxn> {
@@ -239,433 +693,3678 @@ impl Broadcaster {
             .cloned()
             .collect()
     }
+
+    // our part of `epoch` moves out to wait on the neighbours' acks, empty parts too
+    // since the others can't apply the epoch without one from us
+    fn close(&mut self, epoch: usize, me: &str) {
+        let own = self
+            .broadcast_nodes
+            .get_mut(&epoch)
+            .and_then(|x| x.remove(me))
+            .unwrap_or_default();
+        if self.neighborhood.is_empty() {
+            self.buffered -= own.len();
+        } else {
+            let waiting = self.neighborhood.iter().cloned().collect();
+            self.unacked.insert(epoch, (own, waiting));
+        }
+    }
+
+    // (node, epoch, txns) for every part a neighbour didn't ack yet, oldest epoch first
+    fn unacked_parts(&self) -> Vec<(String, usize, Vec<SeqTxn>)> {
+        self.unacked
+            .iter()
+            .flat_map(|(epoch, (txns, waiting))| {
+                waiting.iter().map(|x| (x.clone(), *epoch, txns.clone()))
+            })
+            .collect()
+    }
+
+    fn ack(&mut self, node: &str, epoch: usize) {
+        if let Some((_, waiting)) = self.unacked.get_mut(&epoch) {
+            waiting.remove(node);
+            if waiting.is_empty() {
+                if let Some((txns, _)) = self.unacked.remove(&epoch) {
+                    self.buffered -= txns.len();
+                }
+            }
+        }
+    }
+
+    // txns of every epoch before `cur_epoch` all neighbours sent, oldest epoch first and each epoch once,
+    // the part of `me` is left out since it was applied when the txn came in
+    fn take_ready(&mut self, cur_epoch: usize, me: &str) -> Vec<SeqTxn> {
+        let mut txns = Vec::new();
+        while self.applied < cur_epoch && self.has_all(self.applied) {
+            if let Some(x) = self.broadcast_nodes.get_mut(&self.applied) {
+                if let Some(own) = x.remove(me) {
+                    self.buffered -= own.len();
+                }
+            }
+            let ready = self.get_all(self.applied);
+            self.buffered -= ready.len();
+            txns.extend(ready);
+            self.broadcast_nodes.remove(&self.applied);
+            self.applied += 1;
+        }
+        txns
+    }
 }
 
-struct KVStore {
-    kv: HashMap<usize, usize>,
+// gaps kept per node to estimate the heartbeat interval distribution
+const HEARTBEAT_WINDOW: usize = 100;
+// keeps phi from exploding when heartbeats are very regular
+const MIN_HEARTBEAT_STD_MS: f64 = 10.0;
+// phi of 8 is roughly a 1 in 10^8 chance the node is just late
+const SUSPECT_PHI: f64 = 8.0;
+
+// phi accrual failure detector, instead of a fixed timeout tells how unlikely it is
+// to still not have heard from a node given the gaps between its past heartbeats
+struct FailureDetector {
+    last: HashMap<String, Instant>,
+    gaps: HashMap<String, VecDeque<f64>>,
 }
 
-impl KVStore {
+impl FailureDetector {
+    fn new() -> Self {
+        Self {
+            last: HashMap::new(),
+            gaps: HashMap::new(),
+        }
+    }
+
+    fn heartbeat(&mut self, node: &str) {
+        let now = Instant::now();
+        if let Some(last) = self.last.insert(node.to_string(), now) {
+            let gaps = self.gaps.entry(node.to_string()).or_default();
+            gaps.push_back((now - last).as_secs_f64() * 1000.0);
+            if gaps.len() > HEARTBEAT_WINDOW {
+                gaps.pop_front();
+            }
+        }
+    }
+
+    // 0 until we have at least one gap to compare with
+    fn suspicion_level(&self, node: &str) -> f64 {
+        let (Some(last), Some(gaps)) = (self.last.get(node), self.gaps.get(node)) else {
+            return 0.0;
+        };
+        let n = gaps.len() as f64;
+        let mean = gaps.iter().sum::<f64>() / n;
+        let std = (gaps.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n)
+            .sqrt()
+            .max(MIN_HEARTBEAT_STD_MS);
+        let elapsed = last.elapsed().as_secs_f64() * 1000.0;
+        // logistic approximation of the normal cdf
+        let y = (elapsed - mean) / std;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+// observed remove set, an element is in while one of its add tags was not removed,
+// a remove only cancels the adds it has seen so on a concurrent add/remove the add wins
+// adds are (msg, node, seq), not keyed by msg since int map keys don't survive the flattened `Pl`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct OrSet {
+    adds: HashSet<(usize, String, usize)>,
+    removes: HashSet<(String, usize)>,
+}
+
+impl OrSet {
+    fn add(&mut self, msg: usize, node: String, seq: usize) {
+        self.adds.insert((msg, node, seq));
+    }
+
+    fn remove(&mut self, msg: usize) {
+        for (_, node, seq) in self.adds.iter().filter(|(x, _, _)| *x == msg) {
+            self.removes.insert((node.clone(), *seq));
+        }
+    }
+
+    fn merge(&mut self, other: OrSet) {
+        self.adds.extend(other.adds);
+        self.removes.extend(other.removes);
+    }
+
+    fn elements(&self) -> HashSet<usize> {
+        self.adds
+            .iter()
+            .filter(|(_, node, seq)| !self.removes.contains(&(node.clone(), *seq)))
+            .map(|(msg, _, _)| *msg)
+            .collect()
+    }
+}
+
+// client requests remembered, enough for a retry to still find its first reply
+const DEDUP_CAPACITY: usize = 10_000;
+
+// reply sent to each (client, msg_id), a retried request gets the same answer instead of
+// being applied twice, oldest requests are forgotten first
+struct DedupCache {
+    // `None` for one taken but not answered yet
+    replies: HashMap<(String, usize), Option<Pl>>,
+    order: VecDeque<(String, usize)>,
+}
+
+impl DedupCache {
     fn new() -> Self {
-        Self { kv: HashMap::new() }
+        Self {
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // `resp` is the reply envelope, so the client is `dst` and its msg_id `in_reply_to`
+    fn get(&self, resp: &Msg) -> Option<&Pl> {
+        self.replies
+            .get(&(resp.dst.clone(), resp.body.in_reply_to?))?
+            .as_ref()
+    }
+
+    // false when the request was taken before, answered or not, so a retry while the first
+    // is still in flight doesn't start it again, one without a msg_id can't be told apart and always starts
+    fn start(&mut self, resp: &Msg) -> bool {
+        let Some(client_msg_id) = resp.body.in_reply_to else {
+            return true;
+        };
+        let req = (resp.dst.clone(), client_msg_id);
+        if self.replies.contains_key(&req) {
+            return false;
+        }
+        self.remember(req, None);
+        true
+    }
+
+    fn insert(&mut self, resp: &Msg, pl: Pl) {
+        let Some(client_msg_id) = resp.body.in_reply_to else {
+            return;
+        };
+        self.remember((resp.dst.clone(), client_msg_id), Some(pl));
+    }
+
+    fn remember(&mut self, req: (String, usize), reply: Option<Pl>) {
+        if self.replies.insert(req.clone(), reply).is_none() {
+            self.order.push_back(req);
+            if self.order.len() > DEDUP_CAPACITY {
+                let oldest = self.order.pop_front().unwrap();
+                self.replies.remove(&oldest);
+            }
+        }
     }
+}
 
-    fn apply_transaction(&mut self, txn: &[TxnOp]) -> Vec<TxnOp> {
+// what txns run against, `version` moves with every write so an occ check has something to compare
+trait Store {
+    fn read(&self, key: usize) -> Option<Val>;
+    // returns the value it overwrote
+    fn write(&mut self, key: usize, value: Val) -> Option<Val>;
+    fn version(&self) -> usize;
+
+    // the txn with its reads filled in, and per op the value a write overwrote
+    fn apply_transaction(&mut self, txn: &[TxnOp]) -> (Vec<TxnOp>, Vec<Option<Val>>) {
         txn.iter()
             .map(|&(op, key, value)| match op {
-                'r' => (op, key, self.kv.get(&key).copied()),
+                'r' => ((op, key, self.read(key)), None),
                 'w' => {
-                    self.kv
-                        .insert(key, value.expect("writes MUST contain a value"));
-                    (op, key, value)
+                    let prior = self.write(key, value.expect("writes MUST contain a value"));
+                    ((op, key, value), prior)
                 }
                 _ => panic!(),
             })
-            .collect()
+            .unzip()
     }
 }
 
-fn main() -> Result<()> {
-    let mut state = State::new();
-    let mut broadcaster = Broadcaster::new();
-    let mut kvstore = KVStore::new();
-    // find better way of constructing state
-    // some of those value are never null but some are optionoal
-    // in this structure it's not clear which one is which
-    let mut id = String::new();
-    let mut ids = Vec::new();
-    let mut msg_id = 0;
-    // timestamp
-    // let mut ts = 0;
-    let mut txn_id = 0; // clock
-    let mut stdout = io::stdout().lock();
-    let mut cntr = 0;
-    let mut cntrs = HashMap::new();
-    let (txc, rx) = sync::mpsc::channel();
-    let txsc = txc.clone();
-    let txsm = txc.clone();
-    let mut messages = HashSet::new();
-    let mut seen = HashMap::new();
-    let mut default_neighbourhood = Vec::new();
-    let mut central_neighbourhood = Vec::new();
-    let mut leader = String::new();
-    let mut mesh_neighbourhood: Vec<String> = Vec::new();
-    let mut pending = HashMap::new();
+struct KVStore {
+    kv: HashMap<usize, Val>,
+    version: usize,
+}
+
+impl KVStore {
+    fn new() -> Self {
+        Self {
+            kv: HashMap::new(),
+            version: 0,
+        }
+    }
+}
+
+impl Store for KVStore {
+    fn read(&self, key: usize) -> Option<Val> {
+        self.kv.get(&key).copied()
+    }
+
+    fn write(&mut self, key: usize, value: Val) -> Option<Val> {
+        self.version += 1;
+        self.kv.insert(key, value)
+    }
+
+    fn version(&self) -> usize {
+        self.version
+    }
+}
+
+// one kafka state change, the wal is these as json lines
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WalRecord {
+    Append { key: String, msg: usize },
+    // a replica taking the leader's whole log for `key`
+    Replace { key: String, msgs: Vec<usize> },
+    Commit { key: String, offset: usize },
+}
+
+// append only log of the kafka state so a restarted node picks up where it was,
+// written before the client gets its reply, not fsynced so it only covers process crashes
+struct Wal {
+    file: File,
+}
+
+impl Wal {
+    // replays what is already there, a last line without its `\n` is a write torn
+    // by the crash and gets cut off so new records don't end up glued to it
+    fn open(path: &str) -> Result<(Self, Vec<WalRecord>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let complete = content.rfind('\n').map_or(0, |x| x + 1);
+        if complete < content.len() {
+            eprintln!(
+                "wal {path}: dropping torn tail of {} bytes",
+                content.len() - complete
+            );
+            file.set_len(complete as u64)?;
+        }
+        file.seek(SeekFrom::Start(complete as u64))?;
+        Ok((Self { file }, Self::parse(&content[..complete])?))
+    }
+
+    fn parse(content: &str) -> Result<Vec<WalRecord>> {
+        Ok(content
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?)
+    }
+
+    fn append(&mut self, record: &WalRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+// where the kafka logs and committed offsets live, the handlers only go through this
+trait LogStore {
+    // returns the offset `msg` got
+    fn append(&mut self, key: &str, msg: usize) -> Result<usize>;
+    // a replica taking the leader's whole log for `key`
+    fn replace(&mut self, key: String, msgs: Vec<usize>) -> Result<()>;
+    // msgs of `key` from `offset` on, the one at `offset` first and each next one at the next offset,
+    // `None` for a key never written. a store with gaps between offsets needs another signature
+    fn read_from(&self, key: &str, offset: usize) -> Option<&[usize]>;
+    // offsets only move forward, an older commit is a no-op
+    fn commit(&mut self, key: String, offset: usize) -> Result<()>;
+    fn list_committed(&self, keys: Vec<String>) -> HashMap<String, usize>;
+    // how many msgs each key has
+    fn heads(&self) -> HashMap<String, usize>;
+    // run once when stdin closed, after the last msg was handled
+    fn on_shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default, PartialEq)]
+struct MemLog {
     // msgs by key
-    let mut logs: HashMap<String, Vec<usize>> = HashMap::new();
+    logs: HashMap<String, Vec<usize>>,
     // offset by key
-    let mut committed_offsets: HashMap<String, usize> = HashMap::new();
-    let jhc = thread::spawn(move || {
-        let stdin = io::stdin().lock();
-        for line in stdin.lines() {
-            let line = line?;
-            let req: Msg = serde_json::from_str(&line)?;
-            let evt = Evt::Ext(req);
-            txc.send(evt)?;
-        }
-        Ok::<_, Error>(())
-    });
-    // split into lib and bin per challenge
-    // lib should probably have `State` struct that is impl by bin
-    #[cfg(feature = "broadcast")]
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_millis(1000));
-        if txsc.send(Evt::Int(Task::CentralGossip)).is_err() {
-            break;
-        };
-    });
-    #[cfg(feature = "broadcast")]
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_millis(300));
-        if txsm.send(Evt::Int(Task::MeshGossip)).is_err() {
-            break;
-        };
-    });
-    #[cfg(feature = "g-counter")]
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_millis(10));
-        if txsm.send(Evt::Int(Task::GossipCntr)).is_err() {
-            break;
-        };
-    });
-    #[cfg(feature = "totally")]
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_millis(500));
-        if txsc.send(Evt::Int(Task::SwitchPhase)).is_err() {
-            break;
-        };
-    });
+    committed_offsets: HashMap<String, usize>,
+}
 
-    for evt in rx {
-        match evt {
-            Evt::Ext(msg) => {
-                let mut resp = msg.into_resp(&mut msg_id);
-                match resp.body.pl {
-                    Pl::Error { code, text } => {
-                        eprintln!("===error===");
-                        match code {
-                            30 => {
-                                eprintln!("err:{}\nfrom:{}", text, resp.dst);
-                            }
-                            _ => {
-                                dbg!(code, &text);
-                            }
-                        }
-                    }
-                    Pl::Init { node_id, node_ids } => {
+impl LogStore for MemLog {
+    fn append(&mut self, key: &str, msg: usize) -> Result<usize> {
+        let msgs = self.logs.entry(key.to_string()).or_default();
+        msgs.push(msg);
+        Ok(msgs.len() - 1)
+    }
+
+    fn replace(&mut self, key: String, msgs: Vec<usize>) -> Result<()> {
+        self.logs.insert(key, msgs);
+        Ok(())
+    }
+
+    fn read_from(&self, key: &str, offset: usize) -> Option<&[usize]> {
+        self.logs.get(key).map(|x| &x[offset.min(x.len())..])
+    }
+
+    fn commit(&mut self, key: String, offset: usize) -> Result<()> {
+        self.committed_offsets
+            .entry(key)
+            .and_modify(|x| *x = (*x).max(offset))
+            .or_insert(offset);
+        Ok(())
+    }
+
+    fn list_committed(&self, keys: Vec<String>) -> HashMap<String, usize> {
+        keys.into_iter()
+            .filter_map(|x| self.committed_offsets.get(&x).map(|offset| (x, *offset)))
+            .collect()
+    }
+
+    fn heads(&self) -> HashMap<String, usize> {
+        self.logs
+            .iter()
+            .map(|(k, v)| (k.clone(), v.len()))
+            .collect()
+    }
+}
+
+// debug builds rebuild the state from the wal file this often, in records written
+const WAL_CHECK_EVERY: usize = 1_000;
+
+// in memory, with every change written to the wal first
+struct WalLog {
+    mem: MemLog,
+    wal: Wal,
+    path: String,
+    // records written since the last `check`
+    unchecked: usize,
+}
+
+impl WalLog {
+    fn open(path: &str) -> Result<Self> {
+        let (wal, records) = Wal::open(path)?;
+        eprintln!("wal: replaying {} records", records.len());
+        Ok(Self {
+            mem: Self::replay(records)?,
+            wal,
+            path: path.to_string(),
+            unchecked: 0,
+        })
+    }
+
+    fn replay(records: Vec<WalRecord>) -> Result<MemLog> {
+        let mut mem = MemLog::default();
+        for record in records {
+            match record {
+                WalRecord::Append { key, msg } => {
+                    mem.append(&key, msg)?;
+                }
+                WalRecord::Replace { key, msgs } => mem.replace(key, msgs)?,
+                WalRecord::Commit { key, offset } => mem.commit(key, offset)?,
+            }
+        }
+        Ok(mem)
+    }
+
+    // a record lost or written wrong would otherwise only show after a restart, catch it while
+    // the run that wrote it is still going
+    fn check(&mut self) -> Result<()> {
+        if !cfg!(debug_assertions) {
+            return Ok(());
+        }
+        self.unchecked += 1;
+        if self.unchecked < WAL_CHECK_EVERY {
+            return Ok(());
+        }
+        self.unchecked = 0;
+        let replayed = Self::replay(Wal::parse(&fs::read_to_string(&self.path)?)?)?;
+        assert!(
+            replayed == self.mem,
+            "wal {} replays to a different state than the one in memory",
+            self.path
+        );
+        Ok(())
+    }
+}
+
+impl LogStore for WalLog {
+    fn append(&mut self, key: &str, msg: usize) -> Result<usize> {
+        self.wal.append(&WalRecord::Append {
+            key: key.to_string(),
+            msg,
+        })?;
+        let offset = self.mem.append(key, msg)?;
+        self.check()?;
+        Ok(offset)
+    }
+
+    fn replace(&mut self, key: String, msgs: Vec<usize>) -> Result<()> {
+        self.wal.append(&WalRecord::Replace {
+            key: key.clone(),
+            msgs: msgs.clone(),
+        })?;
+        self.mem.replace(key, msgs)?;
+        self.check()
+    }
+
+    fn read_from(&self, key: &str, offset: usize) -> Option<&[usize]> {
+        self.mem.read_from(key, offset)
+    }
+
+    fn commit(&mut self, key: String, offset: usize) -> Result<()> {
+        self.wal.append(&WalRecord::Commit {
+            key: key.clone(),
+            offset,
+        })?;
+        self.mem.commit(key, offset)?;
+        self.check()
+    }
+
+    fn list_committed(&self, keys: Vec<String>) -> HashMap<String, usize> {
+        self.mem.list_committed(keys)
+    }
+
+    fn heads(&self) -> HashMap<String, usize> {
+        self.mem.heads()
+    }
+
+    // the records are written but may still sit in the page cache
+    fn on_shutdown(&mut self) -> Result<()> {
+        self.wal.file.sync_all()?;
+        Ok(())
+    }
+}
+
+// a restarted neighbour lost everything we marked as seen by it, forget it so it gets the full set again,
+// and it's back so the msgs given up on get their attempts again
+fn reset_seen_for(
+    seen: &mut HashMap<String, RoaringTreemap>,
+    attempts: &mut HashMap<String, HashMap<usize, usize>>,
+    node: &str,
+) {
+    if let Some(seen_by_node) = seen.get_mut(node) {
+        eprintln!(
+            "{node} rejoined, forgetting {} seen msgs",
+            seen_by_node.len()
+        );
+        seen_by_node.clear();
+    }
+    attempts.remove(node);
+}
+
+// a msg gossiped this many times to the same node without an ack is given up on for that node,
+// ~60s of mesh rounds so it still outlasts the partitions maelstrom heals
+const MAX_GOSSIP_ATTEMPTS: usize = 200;
+
+// msgs `host` hasn't acked and that still have attempts left, counting this round as one more
+fn unseen_by(
+    host: &str,
+    messages: &RoaringTreemap,
+    seen: &HashMap<String, RoaringTreemap>,
+    attempts: &mut HashMap<String, HashMap<usize, usize>>,
+) -> HashSet<usize> {
+    let tries = attempts.entry(host.to_string()).or_default();
+    let mut unseen = HashSet::new();
+    for msg in (messages - &seen[host]).iter().map(|x| x as usize) {
+        let n = tries.entry(msg).or_default();
+        if *n >= MAX_GOSSIP_ATTEMPTS {
+            continue;
+        }
+        *n += 1;
+        if *n == MAX_GOSSIP_ATTEMPTS {
+            eprintln!("giving up on {msg} for {host} after {n} unacked gossips");
+        }
+        unseen.insert(msg);
+    }
+    unseen
+}
+
+// the msgs of an acked gossip are seen by its node and stop counting attempts there
+fn gossip_acked(
+    gossip: PendingGossip,
+    seen: &mut HashMap<String, RoaringTreemap>,
+    attempts: &mut HashMap<String, HashMap<usize, usize>>,
+) {
+    if let Some(tries) = attempts.get_mut(&gossip.dst) {
+        tries.retain(|x, _| !gossip.msgs.contains(x));
+    }
+    seen.entry(gossip.dst)
+        .or_default()
+        .extend(gossip.msgs.iter().map(|x| *x as u64));
+}
+
+// a gossip not acked by then is forgotten, its msgs are still unseen by the node and go out again anyway
+const GOSSIP_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// a gossip waiting for its `gossip_ok`
+struct PendingGossip {
+    dst: String,
+    msgs: HashSet<usize>,
+    sent: Instant,
+}
+
+// a `subscribe` holds this long, clients never heartbeat so one that went away is only
+// noticed by not subscribing again
+const SUBSCRIBE_LEASE: Duration = Duration::from_secs(10);
+
+// push new broadcasts to the subscribed clients, dropping the ones whose lease ran out
+fn notify(
+    stdout: &mut impl Write,
+    id: &str,
+    subscribers: &mut HashMap<String, Instant>,
+    msgs: &HashSet<usize>,
+) -> Result<()> {
+    subscribers.retain(|_, x| x.elapsed() < SUBSCRIBE_LEASE);
+    for sub in subscribers.keys() {
+        for msg in msgs {
+            let notification = Msg {
+                src: id.to_string(),
+                dst: sub.clone(),
+                body: Body {
+                    pl: Pl::Notify { msg: *msg },
+                    msg_id: None,
+                    in_reply_to: None,
+                },
+            };
+            notification.send(stdout)?;
+        }
+    }
+    Ok(())
+}
+
+// a differing `digest` range is split in this many
+const DIGEST_FANOUT: usize = 16;
+// a differing range with at most this many msgs is sent whole instead of split again
+const DIGEST_LEAF_MSGS: usize = 32;
+// the root range is 0..this, under 2^53 like the hashes so it survives maelstrom's json
+const DIGEST_MAX: usize = 1 << 53;
+
+// the msgs in lo..hi in order, read off the bitmap without walking the ones before `lo`
+fn msgs_within(msgs: &RoaringTreemap, lo: usize, hi: usize) -> impl Iterator<Item = usize> + '_ {
+    let mut iter = msgs.iter();
+    iter.advance_to(lo as u64);
+    iter.take_while(move |x| *x < hi as u64).map(|x| x as usize)
+}
+
+// hash of the msgs in lo..hi, a sum so it doesn't depend on order and a dense run of msgs
+// costs no more than a sparse one, kept under 2^53 to survive maelstrom's json
+fn range_hash(msgs: &RoaringTreemap, lo: usize, hi: usize) -> (u64, usize) {
+    msgs_within(msgs, lo, hi).fold((0, 0), |(hash, n), x| {
+        // splitmix64 finalizer, spreads neighbouring msgs all over
+        let mut z = (x as u64).wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        (hash.wrapping_add(z ^ (z >> 31)) & ((1 << 53) - 1), n + 1)
+    })
+}
+
+// lo..hi cut in at most `DIGEST_FANOUT` pieces, the last one may be shorter
+fn split_range(lo: usize, hi: usize) -> Vec<(usize, usize)> {
+    let step = (hi - lo).div_ceil(DIGEST_FANOUT);
+    let mut pieces: Vec<_> = (lo..hi)
+        .step_by(step)
+        .map(|x| (x, x.saturating_add(step)))
+        .collect();
+    if let Some(last) = pieces.last_mut() {
+        last.1 = hi;
+    }
+    pieces
+}
+
+// unseen sets at least this big are deflated when built with `compress`
+const COMPRESS_MIN_MSGS: usize = 256;
+
+// what this build can read on top of the plain msgs, advertised in `rejoin`
+const EXTENSIONS: &[&str] = &["deflate"];
+
+// `deflate` only for a peer that said it reads it
+fn gossip_pl(
+    msgs: HashSet<usize>,
+    cntr: Option<HashMap<String, usize>>,
+    deflate: bool,
+    traces: Vec<(usize, Vec<String>)>,
+) -> Result<Pl> {
+    if !cfg!(feature = "compress") || !deflate || msgs.len() < COMPRESS_MIN_MSGS {
+        return Ok(Pl::Gossip {
+            msgs,
+            deflated: None,
+            cntr,
+            traces,
+        });
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, &msgs)?;
+    Ok(Pl::Gossip {
+        msgs: HashSet::new(),
+        deflated: Some(base64::engine::general_purpose::STANDARD.encode(encoder.finish()?)),
+        cntr,
+        traces,
+    })
+}
+
+// the paths of the traced msgs among `msgs`, empty unless built with `trace`
+fn traces_for(
+    traces: &HashMap<usize, Vec<String>>,
+    msgs: &HashSet<usize>,
+) -> Vec<(usize, Vec<String>)> {
+    if !cfg!(feature = "trace") {
+        return Vec::new();
+    }
+    msgs.iter()
+        .filter_map(|x| traces.get(x).map(|path| (*x, path.clone())))
+        .collect()
+}
+
+fn inflate(deflated: &str) -> Result<HashSet<usize>> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(deflated)?;
+    let mut json = String::new();
+    DeflateDecoder::new(&bytes[..]).read_to_string(&mut json)?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+// where `generate_ok` ids come from
+trait IdGenerator {
+    fn next_id(&mut self) -> String;
+}
+
+struct UuidV7;
+
+impl IdGenerator for UuidV7 {
+    fn next_id(&mut self) -> String {
+        Uuid::now_v7().to_string()
+    }
+}
+
+// mixed with the node position so every node walks its own sequence
+const ID_SEED: u64 = 0x5eed;
+
+// same ids on every run with `seeded-ids`, for comparing outputs between runs
+struct SeededIds {
+    node: u64,
+    seq: u64,
+}
+
+impl IdGenerator for SeededIds {
+    fn next_id(&mut self) -> String {
+        self.seq += 1;
+        Uuid::from_u64_pair(ID_SEED ^ self.node, self.seq).to_string()
+    }
+}
+
+// maelstrom ids are `n0`, `n1`, ... order them by number so `n2 < n10`,
+// anything else goes after them in string order
+fn cmp_node_ids(a: &str, b: &str) -> Ordering {
+    let num = |x: &str| x.strip_prefix('n').and_then(|x| x.parse::<usize>().ok());
+    match (num(a), num(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+// points each node gets on the ring, more of them spread the keys more evenly
+#[cfg(test)]
+const RING_VNODES: usize = 16;
+
+// fnv-1a, every node has to hash a key to the same point, std's hasher doesn't promise that across builds
+#[cfg(test)]
+fn ring_hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+// who this node talks to, derived once from `init` and then from the `topology` msg
+#[derive(Debug, Default)]
+struct Topology {
+    #[cfg(test)]
+    id: String,
+    leader: String,
+    // everyone talks to the leader, the leader talks to everyone
+    star: Vec<String>,
+    // everyone but self
+    mesh: Vec<String>,
+    // what maelstrom suggests in `topology`, no gossip uses it for now
+    #[allow(dead_code)]
+    maelstrom: Vec<String>,
+    // consistent hash ring, (point, node) sorted by point, a node leaving only moves its own keys,
+    // only built in tests until something shards by key
+    #[cfg(test)]
+    ring: Vec<(u64, String)>,
+}
+
+impl Topology {
+    // `ids` must be sorted with `cmp_node_ids` and contain `id`
+    fn new(id: &str, ids: &[String]) -> Self {
+        let leader = ids.first().cloned().unwrap_or_default();
+        Self {
+            #[cfg(test)]
+            id: id.to_string(),
+            #[cfg(test)]
+            ring: Self::ring(ids),
+            star: Self::star(id, &leader, ids),
+            mesh: ids.iter().filter(|x| *x != id).cloned().collect(),
+            leader,
+            maelstrom: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    fn ring(ids: &[String]) -> Vec<(u64, String)> {
+        let mut ring: Vec<_> = ids
+            .iter()
+            .flat_map(|x| {
+                (0..RING_VNODES).map(move |i| (ring_hash(&format!("{x}#{i}")), x.clone()))
+            })
+            .collect();
+        ring.sort();
+        ring
+    }
+
+    fn star(id: &str, leader: &str, ids: &[String]) -> Vec<String> {
+        if id == leader {
+            ids.iter().filter(|x| *x != leader).cloned().collect()
+        } else {
+            vec![leader.to_string()]
+        }
+    }
+
+    fn set_maelstrom(&mut self, id: &str, topology: &HashMap<String, Vec<String>>) {
+        self.maelstrom = topology.get(id).cloned().unwrap_or_default();
+    }
+
+    fn leader(&self) -> &str {
+        &self.leader
+    }
+
+    fn mesh(&self) -> &[String] {
+        &self.mesh
+    }
+
+    fn central(&self) -> &[String] {
+        &self.star
+    }
+
+    // first node clockwise from the key on the ring
+    #[cfg(test)]
+    fn owner_of(&self, key: &str) -> &str {
+        let point = ring_hash(key);
+        let i = self.ring.partition_point(|(x, _)| *x < point);
+        self.ring
+            .get(i)
+            .or(self.ring.first())
+            .map_or("", |(_, node)| node)
+    }
+
+    #[cfg(test)]
+    fn is_owner(&self, key: &str) -> bool {
+        self.owner_of(key) == self.id
+    }
+}
+
+// rough size of the `[offset,msg]` pairs in a `poll_ok`, past it the client gets
+// a partial answer and polls again from where it stopped
+const POLL_MAX_BYTES: usize = 64 * 1024;
+
+// `[offset, msg]` pairs per key, ascending by offset as `read_from` hands them out in order
+fn poll_msgs(
+    store: &dyn LogStore,
+    offsets: HashMap<String, usize>,
+) -> HashMap<String, Vec<(usize, usize)>> {
+    let mut bytes = 0;
+    offsets
+        .into_iter()
+        .filter_map(|(key, offset)| {
+            let msgs = store.read_from(&key, offset)?;
+            let mut polled = Vec::new();
+            for (i, msg) in msgs.iter().enumerate().map(|(i, msg)| (i + offset, msg)) {
+                let len = format!("[{i},{msg}],").len();
+                // at least one per key so every key makes progress
+                if !polled.is_empty() && bytes + len > POLL_MAX_BYTES {
+                    break;
+                }
+                bytes += len;
+                polled.push((i, *msg));
+            }
+            Some((key, polled))
+        })
+        .collect()
+}
+
+// `neighbours` starting one further every round, so when a round gets cut short
+// it's not always the same last ones left out
+fn rotated(neighbours: &[String], round: usize) -> impl Iterator<Item = &String> {
+    let start = round % neighbours.len().max(1);
+    neighbours[start..].iter().chain(&neighbours[..start])
+}
+
+// counter gossip rounds between two that send every counter whatever was sent before,
+// ~1s at 10ms a round, in case the one that carried a change got lost
+const CNTR_FULL_SYNC_EVERY: usize = 100;
+
+// how the counters of every node are stored, merged and read back
+trait CounterCrdt {
+    // a delta added on this node
+    fn add(&mut self, name: String, delta: usize);
+    // this node's own part, the one that gets gossiped
+    fn local(&self) -> &HashMap<String, usize>;
+    // `cntr` is the own part `node` gossiped, gives back the counters it raised
+    fn merge(&mut self, node: &str, cntr: HashMap<String, usize>) -> Vec<String>;
+    fn value(&self, name: &str) -> usize;
+}
+
+// every node only grows its own part, so keep the max seen per node and sum those,
+// a stale gossip showing up late can't take anything back
+#[derive(Default)]
+struct GCounter {
+    // by counter name
+    own: HashMap<String, usize>,
+    // by node then counter name
+    peers: HashMap<String, HashMap<String, usize>>,
+}
+
+impl CounterCrdt for GCounter {
+    fn add(&mut self, name: String, delta: usize) {
+        *self.own.entry(name).or_default() += delta;
+    }
+
+    fn local(&self) -> &HashMap<String, usize> {
+        &self.own
+    }
+
+    fn merge(&mut self, node: &str, cntr: HashMap<String, usize>) -> Vec<String> {
+        let peer = self.peers.entry(node.to_string()).or_default();
+        let mut raised = Vec::new();
+        for (name, value) in cntr {
+            let x = peer.entry(name.clone()).or_default();
+            if value > *x {
+                *x = value;
+                raised.push(name);
+            }
+        }
+        raised
+    }
+
+    fn value(&self, name: &str) -> usize {
+        self.peers
+            .values()
+            .filter_map(|x| x.get(name))
+            .sum::<usize>()
+            + self.own.get(name).copied().unwrap_or_default()
+    }
+}
+
+fn send_kv(stdout: &mut impl Write, src: &str, msg_id: &mut usize, pl: Pl) -> Result<usize> {
+    let kv_msg_id = *msg_id;
+    *msg_id += 1;
+    let req = Msg {
+        src: src.to_string(),
+        dst: SEQ_KV.to_string(),
+        body: Body {
+            pl,
+            msg_id: Some(kv_msg_id),
+            in_reply_to: None,
+        },
+    };
+    req.send(stdout)?;
+    Ok(kv_msg_id)
+}
+
+// request ids start here, clear of the reply ids counting up from 0 and still exact as a json number
+const RPC_ID_BASE: usize = 1 << 48;
+
+// remember what a request with `msg_id` was for until its reply shows up,
+// a msg_id still in there means replies would reach the wrong waiter
+fn await_reply<T>(pending: &mut HashMap<usize, T>, msg_id: usize, waiter: T) {
+    let prev = pending.insert(msg_id, waiter);
+    debug_assert!(
+        prev.is_none(),
+        "msg_id {msg_id} reused while still awaiting a reply"
+    );
+}
+
+// forwards waiting on the leader, past this the client is told to back off instead
+const MAX_RELAYS: usize = 1_000;
+// a forward not answered by then is sent again, under the same msg_id so the leader
+// dedups a send it already applied
+const RELAY_RETRY: Duration = Duration::from_millis(200);
+// and given up on after this, the client gets a timeout as the write may or may not have happened
+const RELAY_TIMEOUT: Duration = Duration::from_secs(2);
+// how long a counter read waits on its `at_least` before answering anyway
+const READ_BARRIER_TIMEOUT: Duration = Duration::from_millis(500);
+
+// the leader waits this long for a majority to have a send before telling the client it timed out
+const QUORUM_TIMEOUT: Duration = Duration::from_secs(1);
+
+// a send appended on the leader whose `send_ok` waits on replica acks
+struct QuorumWrite {
+    client: Msg,
+    offset: usize,
+    acked: HashSet<String>,
+    // acks that make a majority together with the leader
+    needed: usize,
+    first: Instant,
+}
+
+// a client request passed on to the leader
+struct Relay {
+    client: Msg,
+    fwd: Msg,
+    first: Instant,
+    last: Instant,
+}
+
+// pass a client request on to `dst`, the reply comes back to this node and
+// goes out to the client through `relays`, keyed by the forwarded msg_id
+fn forward(
+    stdout: &mut impl Write,
+    relays: &mut HashMap<usize, Relay>,
+    rpc_id: &mut usize,
+    client: Msg,
+    dst: &str,
+    pl: Pl,
+) -> Result<()> {
+    if relays.len() >= MAX_RELAYS {
+        return client.reply(
+            Pl::Error {
+                code: 11,
+                text: format!("{MAX_RELAYS} requests already waiting on {dst}"),
+            },
+            stdout,
+        );
+    }
+    let mut fwd = client.clone();
+    fwd.dst = dst.to_string();
+    fwd.body.pl = pl;
+    fwd.body.msg_id = Some(*rpc_id);
+    fwd.body.in_reply_to = None;
+    *rpc_id += 1;
+    fwd.clone().send(stdout)?;
+    let now = Instant::now();
+    await_reply(
+        relays,
+        fwd.body.msg_id.unwrap(),
+        Relay {
+            client,
+            fwd,
+            first: now,
+            last: now,
+        },
+    );
+    Ok(())
+}
+
+// heartbeats between two where the leader sends replicas every committed offset whatever was sent
+// before, ~1s at 100ms a heartbeat, the `commit_offsets` it replicates aren't acked and may get lost
+const OFFSETS_FULL_SYNC_EVERY: usize = 10;
+
+// the committed offsets each replica wasn't sent yet, all of them on a full sync
+fn replicate_offsets(
+    stdout: &mut impl Write,
+    id: &str,
+    replicas: &[String],
+    committed: &HashMap<String, usize>,
+    sent_offsets: &mut HashMap<String, HashMap<String, usize>>,
+    full_sync: bool,
+) -> Result<()> {
+    for x in replicas {
+        let sent = sent_offsets.entry(x.clone()).or_default();
+        let delta: HashMap<_, _> = committed
+            .iter()
+            .filter(|(k, v)| full_sync || sent.get(*k).is_none_or(|x| x < v))
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        if delta.is_empty() {
+            continue;
+        }
+        sent.extend(delta.clone());
+        let msg_to_replic = Msg {
+            src: id.to_string(),
+            dst: x.clone(),
+            body: Body {
+                pl: Pl::CommitOffsets { offsets: delta },
+                msg_id: None,
+                in_reply_to: None,
+            },
+        };
+        msg_to_replic.send(stdout)?;
+    }
+    Ok(())
+}
+
+// how the gossip and retry timers space their ticks, nodes that all went back to retrying
+// when a partition healed would otherwise keep firing in step
+#[derive(Clone, Copy)]
+enum Jitter {
+    // exactly `every` apart
+    Off,
+    // anywhere in [0, 2 * every), same rate on average
+    Full,
+    // anywhere from every / 2 to 3x the previous sleep, capped at 2 * every
+    Decorrelated,
+}
+
+const GOSSIP_JITTER: Jitter = if cfg!(feature = "full-jitter") {
+    Jitter::Full
+} else if cfg!(feature = "decorrelated-jitter") {
+    Jitter::Decorrelated
+} else {
+    Jitter::Off
+};
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis() as u64)
+}
+
+// nodes start within the same ms, the pid keeps them apart, `| 1` as xorshift gets stuck on 0
+fn rng_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos() as u64)
+        ^ u64::from(std::process::id())
+        | 1
+}
+
+// xorshift64, ticks only have to differ between nodes, not worth a crate
+fn xorshift(x: &mut u64) -> u64 {
+    *x ^= *x << 13;
+    *x ^= *x >> 7;
+    *x ^= *x << 17;
+    *x
+}
+
+impl Jitter {
+    fn next(self, every: Duration, prev: Duration, rng: &mut u64) -> Duration {
+        // uniform in [lo, hi)
+        let between = |lo: Duration, hi: Duration, rng: &mut u64| {
+            let span = hi.saturating_sub(lo).as_nanos() as u64;
+            if span == 0 {
+                return lo;
+            }
+            lo + Duration::from_nanos(xorshift(rng) % span)
+        };
+        match self {
+            Jitter::Off => every,
+            Jitter::Full => between(Duration::ZERO, every * 2, rng),
+            Jitter::Decorrelated => between(every / 2, prev * 3, rng).min(every * 2),
+        }
+    }
+}
+
+// timer thread handing `task` to the loop every `every`, stops once the loop is gone
+fn spawn_interval(tx: &SyncSender<Evt>, every: Duration, task: Task) -> JoinHandle<()> {
+    spawn_jittered(tx, every, Jitter::Off, task)
+}
+
+fn spawn_jittered(
+    tx: &SyncSender<Evt>,
+    every: Duration,
+    jitter: Jitter,
+    task: Task,
+) -> JoinHandle<()> {
+    let tx = tx.clone();
+    let mut rng = rng_seed();
+    let mut sleep = every;
+    thread::spawn(move || loop {
+        sleep = jitter.next(every, sleep, &mut rng);
+        thread::sleep(sleep);
+        // a full channel means the loop is behind, skip this tick rather than pile up more
+        if let Err(TrySendError::Disconnected(_)) = tx.try_send(Evt::Int(task.clone())) {
+            break;
+        }
+    })
+}
+
+fn main() -> Result<()> {
+    let mut state = State::new();
+    let mut broadcaster = Broadcaster::new();
+    let mut kvstore = KVStore::new();
+    let mut detector = FailureDetector::new();
+    let mut dedup = DedupCache::new();
+    // find better way of constructing state
+    // some of those value are never null but some are optionoal
+    // in this structure it's not clear which one is which
+    let mut id = String::new();
+    let mut msg_id = 0;
+    let mut msgs_in = 0;
+    let mut msgs_by_type: HashMap<&'static str, usize> = HashMap::new();
+    let mut relay_retries = 0;
+    let mut unknown_in = 0;
+    // timestamp
+    // let mut ts = 0;
+    // what `read` answers for, a node built with both puts both in its `read_ok`
+    let serves_broadcast = cfg!(feature = "broadcast");
+    let serves_counter = cfg!(feature = "g-counter");
+    // flushed by the loop as `FLUSH_POLICY` says
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    // events handled since the last flush
+    let mut unflushed = 0;
+    let mut counter = GCounter::default();
+    // counter name -> unix ms of the last gossip that raised it, see `read-freshness`
+    let mut merged_at: HashMap<String, u64> = HashMap::new();
+    let mut id_gen: Box<dyn IdGenerator> = Box::new(UuidV7);
+    let mut kv_pending: HashMap<usize, KvOp> = HashMap::new();
+    // the reader blocks once this many events wait for the loop
+    let (txc, rx) = sync::mpsc::sync_channel(EVT_CAPACITY);
+    // timer tasks get their own channel so they don't queue behind client msgs
+    let (txi, rxi) = sync::mpsc::sync_channel(EVT_CAPACITY);
+    #[cfg(feature = "slow-echo")]
+    let txse = txi.clone();
+    // seq-kv adds whose cas lost come back through here once their backoff is over
+    let txkv = txi.clone();
+    let mut cas_rng = rng_seed();
+    // a bitmap, the broadcast msgs are mostly dense runs of small ints
+    let mut messages = RoaringTreemap::new();
+    // clients getting a `notify` per new broadcast, with when they last subscribed
+    let mut subscribers: HashMap<String, Instant> = HashMap::new();
+    let mut orset = OrSet::default();
+    // makes every add of this node unique in the or-set
+    let mut orset_tag = 0;
+    // node -> msgs it acked, a bitmap like `messages` so the diff per round stays cheap
+    let mut seen: HashMap<String, RoaringTreemap> = HashMap::new();
+    // node -> msg -> times gossiped to it without an ack, see `unseen_by`
+    let mut gossip_attempts: HashMap<String, HashMap<usize, usize>> = HashMap::new();
+    // path each traced msg took to reach this node
+    let mut traces: HashMap<usize, Vec<String>> = HashMap::new();
+    // on the origin of a traced msg, the paths every node reported back
+    let mut trace_paths: HashMap<usize, Vec<Vec<String>>> = HashMap::new();
+    // what each neighbour said it can read in its `rejoin`
+    let mut peer_extensions: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut topology = Topology::default();
+    // move the gossip starting neighbour along, see `rotated`, one per task so each goes round on its own
+    let mut central_round = 0;
+    let mut mesh_round = 0;
+    let mut entropy_round = 0;
+    // neighbour -> own counters as last gossiped to it, unchanged ones aren't sent again
+    let mut cntr_sent: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    let mut cntr_rounds = 0;
+    // see `GOSSIP_SHED_QUEUED`
+    let mut gossip_shed = 0;
+    let mut shed_last_round = false;
+    // gossip msg_id -> what went where, until its `gossip_ok`
+    let mut pending: HashMap<usize, PendingGossip> = HashMap::new();
+    // kafka msgs and committed offsets by key
+    let mut store: Box<dyn LogStore> = Box::new(MemLog::default());
+    // (key, token) -> offset the tokened send was appended at
+    let mut send_tokens: HashMap<(String, String), usize> = HashMap::new();
+    // forwarded msg_id -> client reply envelope
+    let mut relays: HashMap<usize, Relay> = HashMap::new();
+    // msg_id of our own requests to other nodes and services, replies use `msg_id`
+    let mut rpc_id = RPC_ID_BASE;
+    // on replicas, the leader's log length per key as of its last heartbeat
+    let mut leader_heads: HashMap<String, usize> = HashMap::new();
+    // on the leader, replica -> committed offsets already replicated to it
+    let mut sent_offsets: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    let mut heartbeat_rounds = 0;
+    // replication msg_id -> send waiting on its quorum, the same msg_id goes to every replica
+    let mut quorum_writes: HashMap<usize, QuorumWrite> = HashMap::new();
+    // (client, msg_id) of the sends in `quorum_writes`, a retry of one must not append again
+    let mut quorum_clients: HashSet<(String, usize)> = HashSet::new();
+    // counter reads held on their `at_least`: (client, counter, at_least, deadline)
+    let mut barrier_reads: Vec<(Msg, String, usize, Instant)> = Vec::new();
+    // msgs read but not yet taken by the loop
+    let queued = Arc::new(AtomicUsize::new(0));
+    let queued_in = queued.clone();
+    let jhc = thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        // length prefixed frames instead of maelstrom's one json per line, for other transports
+        if cfg!(feature = "framed") {
+            while let Some(req) = Msg::read_frame(&mut stdin)? {
+                queued_in.fetch_add(1, atomic::Ordering::Relaxed);
+                txc.send(Evt::Ext(req))?;
+            }
+            txc.send(Evt::Eof)?;
+            return Ok(());
+        }
+        for line in stdin.lines() {
+            let line = line?;
+            let req: Msg = serde_json::from_str(&line)?;
+            let evt = Evt::Ext(req);
+            queued_in.fetch_add(1, atomic::Ordering::Relaxed);
+            txc.send(evt)?;
+        }
+        txc.send(Evt::Eof)?;
+        Ok::<_, Error>(())
+    });
+    // split into lib and bin per challenge
+    // lib should probably have `State` struct that is impl by bin
+    if cfg!(feature = "broadcast") {
+        spawn_jittered(
+            &txi,
+            Duration::from_millis(1000),
+            GOSSIP_JITTER,
+            Task::CentralGossip,
+        );
+        spawn_jittered(
+            &txi,
+            Duration::from_millis(300),
+            GOSSIP_JITTER,
+            Task::MeshGossip,
+        );
+    }
+    // with `piggyback` the counters go out with the mesh gossip instead
+    if cfg!(feature = "g-counter") && !cfg!(feature = "piggyback") {
+        spawn_jittered(
+            &txi,
+            Duration::from_millis(10),
+            GOSSIP_JITTER,
+            Task::GossipCntr,
+        );
+    }
+    if cfg!(feature = "kafka") {
+        spawn_interval(&txi, Duration::from_millis(100), Task::Heartbeat);
+        spawn_jittered(&txi, RELAY_RETRY, GOSSIP_JITTER, Task::RetryRelays);
+    }
+    if cfg!(feature = "merkle") {
+        spawn_jittered(
+            &txi,
+            Duration::from_millis(1000),
+            GOSSIP_JITTER,
+            Task::AntiEntropy,
+        );
+    }
+    if let FlushPolicy::Interval { every } = FLUSH_POLICY {
+        spawn_interval(&txi, every, Task::Flush);
+    }
+    if cfg!(feature = "totally") {
+        spawn_interval(&txi, Duration::from_millis(500), Task::SwitchPhase);
+    }
+    // only the timers and `txkv` keep the internal side open from here
+    drop(txi);
+
+    loop {
+        // answer the held reads once gossip caught up, or with what we have when out of time
+        if !barrier_reads.is_empty() {
+            let now = Instant::now();
+            for (client, name, at_least, deadline) in mem::take(&mut barrier_reads) {
+                let value = counter.value(&name);
+                if value < at_least && now < deadline {
+                    barrier_reads.push((client, name, at_least, deadline));
+                    continue;
+                }
+                client.reply(
+                    Pl::ReadOk {
+                        msgs: None,
+                        value: Some(value as i64),
+                        behind: (value < at_least).then_some(true),
+                        merged_at: merged_at
+                            .get(&name)
+                            .copied()
+                            .filter(|_| cfg!(feature = "read-freshness")),
+                    },
+                    &mut stdout,
+                )?;
+            }
+        }
+        let flush = match FLUSH_POLICY {
+            FlushPolicy::PerEvent => true,
+            FlushPolicy::Drained => queued.load(atomic::Ordering::Relaxed) == 0,
+            FlushPolicy::EveryN { n } => unflushed >= n,
+            FlushPolicy::Interval { .. } => false,
+        };
+        if flush {
+            stdout.flush()?;
+            unflushed = 0;
+        }
+        // internal first, so a backlog of client msgs can't hold gossip back
+        let evt = match rxi.try_recv() {
+            Ok(evt) => evt,
+            Err(_) => match rx.recv_timeout(INT_POLL) {
+                Ok(evt) => evt,
+                Err(sync::mpsc::RecvTimeoutError::Timeout) => {
+                    stdout.flush()?;
+                    unflushed = 0;
+                    continue;
+                }
+                Err(sync::mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+        };
+        unflushed += 1;
+        match evt {
+            Evt::Ext(msg) => {
+                msgs_in += 1;
+                *msgs_by_type.entry((&msg.body.pl).into()).or_default() += 1;
+                let backlog = queued.fetch_sub(1, atomic::Ordering::Relaxed) - 1;
+                // lost by `into_resp`, needed to match replies with what we asked,
+                // only ids from `rpc_id` are ours to wait on, the rest answered a reply we sent
+                let in_reply_to = msg.body.in_reply_to.filter(|x| *x >= RPC_ID_BASE);
+                let mut resp = msg.into_resp(&mut msg_id);
+                // take the request payload out so `resp` stays whole for `reply`,
+                // whatever is left in there gets overwritten before sending
+                match mem::replace(&mut resp.body.pl, Pl::InitOk) {
+                    Pl::Error { code, text }
+                        if in_reply_to.is_some_and(|x| kv_pending.contains_key(&x)) =>
+                    {
+                        match (code, kv_pending.remove(&in_reply_to.unwrap()).unwrap()) {
+                            // key does not exist yet
+                            (20, KvOp::Read { client }) => {
+                                client.reply(
+                                    Pl::ReadOk {
+                                        msgs: None,
+                                        value: Some(0),
+                                        behind: None,
+                                        merged_at: None,
+                                    },
+                                    &mut stdout,
+                                )?;
+                            }
+                            (
+                                20,
+                                KvOp::Add {
+                                    client,
+                                    key,
+                                    delta,
+                                    tries,
+                                },
+                            ) => {
+                                let kv_msg_id = send_kv(
+                                    &mut stdout,
+                                    &id,
+                                    &mut rpc_id,
+                                    Pl::Cas {
+                                        key: key.clone(),
+                                        from: 0,
+                                        to: delta,
+                                        create_if_not_exists: true,
+                                    },
+                                )?;
+                                await_reply(
+                                    &mut kv_pending,
+                                    kv_msg_id,
+                                    KvOp::Add {
+                                        client,
+                                        key,
+                                        delta,
+                                        tries,
+                                    },
+                                );
+                            }
+                            (_, KvOp::Read { client } | KvOp::Sync { client, .. }) => {
+                                client.reply(Pl::Error { code, text }, &mut stdout)?;
+                            }
+                            // 22 is someone else's cas going first, anything else is worth a retry too,
+                            // after a backoff so nodes losing to each other don't just go again right away
+                            (
+                                _,
+                                KvOp::Add {
+                                    client,
+                                    key,
+                                    delta,
+                                    tries,
+                                },
+                            ) => {
+                                let cap = CAS_BACKOFF
+                                    .saturating_mul(1 << tries.min(16))
+                                    .min(CAS_BACKOFF_MAX);
+                                let backoff = Duration::from_nanos(
+                                    xorshift(&mut cas_rng) % cap.as_nanos() as u64,
+                                );
+                                let txkv = txkv.clone();
+                                thread::spawn(move || {
+                                    thread::sleep(backoff);
+                                    let _ = txkv.send(Evt::Int(Task::RetryAdd {
+                                        client: Box::new(client),
+                                        key,
+                                        delta,
+                                        tries: tries + 1,
+                                    }));
+                                });
+                            }
+                        }
+                    }
+                    // whatever the leader answered to a forwarded request, error included
+                    pl if in_reply_to.is_some_and(|x| relays.contains_key(&x)) => {
+                        let relay = relays.remove(&in_reply_to.unwrap()).unwrap();
+                        // a client retry gets this answer again rather than a second forward, an error
+                        // like 11 is left out so the retry is tried anew
+                        if !matches!(pl, Pl::Error { .. }) {
+                            dedup.insert(&relay.client, pl.clone());
+                        }
+                        relay.client.reply(pl, &mut stdout)?;
+                    }
+                    // the leader answering a resend of a relay we already answered, or after `RELAY_TIMEOUT`
+                    Pl::SendOk { .. }
+                    | Pl::PollOk { .. }
+                    | Pl::CommitOffsetsOk
+                    | Pl::ListCommittedOffsetsOk { .. }
+                        if in_reply_to.is_some() =>
+                    {
+                        eprintln!(
+                            "late reply from {} to {}, no relay waits on it",
+                            resp.dst,
+                            in_reply_to.unwrap()
+                        );
+                    }
+                    // maelstrom clients are `c<n>`, other nodes' msgs still go through so gossip can drain
+                    _ if backlog > OVERLOAD_QUEUED && resp.dst.starts_with('c') => {
+                        resp.reply(
+                            Pl::Error {
+                                code: 11,
+                                text: format!("overloaded, {backlog} msgs queued"),
+                            },
+                            &mut stdout,
+                        )?;
+                    }
+                    Pl::Broadcast { .. } | Pl::Add { .. } | Pl::Send { .. }
+                        if dedup.get(&resp).is_some() =>
+                    {
+                        let cached = dedup.get(&resp).unwrap().clone();
+                        resp.reply(cached, &mut stdout)?;
+                    }
+                    // still waiting on its quorum, the first one gets the answer
+                    Pl::Send { .. }
+                        if resp
+                            .body
+                            .in_reply_to
+                            .is_some_and(|x| quorum_clients.contains(&(resp.dst.clone(), x))) => {}
+                    // a client retry of a request still forwarded to the leader, the relay answers it
+                    Pl::Send { .. } | Pl::CommitOffsets { .. }
+                        if resp.body.in_reply_to.is_some()
+                            && relays.values().any(|x| {
+                                x.client.dst == resp.dst
+                                    && x.client.body.in_reply_to == resp.body.in_reply_to
+                            }) => {}
+                    Pl::Error { code, text } => {
+                        eprintln!("===error===");
+                        match code {
+                            30 => {
+                                eprintln!("err:{}\nfrom:{}", text, resp.dst);
+                            }
+                            _ => {
+                                dbg!(code, &text);
+                            }
+                        }
+                    }
+                    // nothing to derive the neighbourhoods from, stay uninitialised
+                    Pl::Init { node_id, node_ids } if node_id.is_empty() || node_ids.is_empty() => {
+                        resp.reply(
+                            Pl::Error {
+                                code: 12,
+                                text: format!(
+                                    "malformed init, node_id {node_id:?} node_ids {node_ids:?}"
+                                ),
+                            },
+                            &mut stdout,
+                        )?;
+                    }
+                    Pl::Init { node_id, node_ids } => {
                         id = node_id.clone();
-                        ids = node_ids.clone();
-                        let central = ids.first().unwrap().clone();
-                        central_neighbourhood = if id == *central {
-                            ids.iter().filter(|x| **x != central).cloned().collect()
-                        } else {
-                            vec![central.clone()]
-                        };
-                        leader = central;
-                        mesh_neighbourhood = ids.iter().filter(|x| **x != id).cloned().collect();
+                        let mut ids = node_ids;
+                        ids.retain(|x| !SERVICE_NODES.contains(&x.as_str()));
+                        // `seen` expects to have self in there
+                        if !ids.contains(&id) {
+                            eprintln!("warning: {id} missing from node_ids {ids:?}, adding it");
+                            ids.push(id.clone());
+                        }
+                        // leader and star center are the first id, make it `n0` and not whatever sorts first as a string
+                        ids.sort_by(|a, b| cmp_node_ids(a, b));
+                        topology = Topology::new(&id, &ids);
+                        // gossip to nodes that left will never be acked, stop counting it
+                        pending.retain(|_, x| topology.mesh().contains(&x.dst));
+                        gossip_attempts.retain(|node, _| topology.mesh().contains(node));
+                        if cfg!(feature = "wal") {
+                            store = Box::new(WalLog::open(&format!("{id}.wal"))?);
+                        }
+                        let position = ids.iter().position(|x| *x == id).unwrap() as u64;
+                        if cfg!(feature = "seeded-ids") {
+                            id_gen = Box::new(SeededIds {
+                                node: position,
+                                seq: 0,
+                            });
+                        }
+                        if cfg!(feature = "lossy") {
+                            LOSS_RNG.store(loss_seed(position), atomic::Ordering::Relaxed);
+                        }
                         // self is included but never used
-                        seen = ids.iter().map(|id| (id.clone(), HashSet::new())).collect();
-                        // self included but equal 0
-                        cntrs = ids.iter().map(|id| (id.clone(), 0)).collect();
-                        resp.body.pl = Pl::InitOk;
-                        resp.send(&mut stdout)?;
+                        seen = ids
+                            .iter()
+                            .map(|id| (id.clone(), RoaringTreemap::new()))
+                            .collect();
+                        resp.reply(Pl::InitOk, &mut stdout)?;
                         // double check for all those clones after all challenges solved
-                        broadcaster.init(mesh_neighbourhood.clone());
+                        broadcaster.init(topology.mesh().to_vec());
+                        if cfg!(feature = "broadcast") {
+                            for x in topology.mesh() {
+                                let rejoin = Msg {
+                                    src: id.clone(),
+                                    dst: x.clone(),
+                                    body: Body {
+                                        pl: Pl::Rejoin {
+                                            extensions: EXTENSIONS
+                                                .iter()
+                                                .map(|x| x.to_string())
+                                                .collect(),
+                                        },
+                                        msg_id: None,
+                                        in_reply_to: None,
+                                    },
+                                };
+                                rejoin.send(&mut stdout)?;
+                            }
+                        }
+                    }
+                    Pl::Txn { txn, .. } if txn_error(broadcaster.buffered, txn.len()).is_some() => {
+                        let err = txn_error(broadcaster.buffered, txn.len()).unwrap();
+                        resp.reply(err, &mut stdout)?;
                     }
-                    Pl::Txn { txn } => {
+                    Pl::Txn { txn, prior } => {
                         let txn_id = state.next_txn_id();
-                        let result = kvstore.apply_transaction(&txn);
+                        let (result, priors) = kvstore.apply_transaction(&txn);
                         broadcaster.push(
-                            txn_id,
+                            state.cur_epoch,
                             id.clone(),
                             vec![SeqTxn {
                                 seq: txn_id,
                                 txn: result.clone(),
                             }],
                         );
-                        resp.body.pl = Pl::TxnOk { txn: result };
-                        resp.send(&mut stdout)?;
+                        resp.reply(
+                            Pl::TxnOk {
+                                txn: result,
+                                prior: if prior { priors } else { Vec::new() },
+                            },
+                            &mut stdout,
+                        )?;
+                    }
+                    Pl::BroadcastTxn { epoch, txns } => {
+                        eprintln!(
+                            "broadcast txn recv of len {} for epoch {}",
+                            txns.len(),
+                            epoch
+                        );
+                        // buffered under the sender epoch, applied once every neighbour sent theirs for it
+                        broadcaster.push(epoch, resp.dst.clone(), txns);
+                        for txn in broadcaster.take_ready(state.cur_epoch, &id) {
+                            kvstore.apply_transaction(&txn.txn);
+                        }
+                        // a repeat is acked too, the ack before it may be the one that got lost
+                        resp.reply(Pl::BroadcastTxnOk { epoch }, &mut stdout)?;
                     }
-                    Pl::BroadcastTxn { txns } => {
-                        eprintln!("broadcast txn recv of len {}", txns.len());
-                        broadcaster.push(state.txn_id, resp.dst.clone(), txns);
+                    Pl::BroadcastTxnOk { epoch } => {
+                        broadcaster.ack(&resp.dst, epoch);
                     }
                     Pl::Echo { echo } => {
-                        resp.body.pl = Pl::EchoOk { echo };
-                        resp.send(&mut stdout)?;
+                        #[cfg(feature = "slow-echo")]
+                        {
+                            // sleep on its own thread, the loop keeps serving in the meantime
+                            resp.body.pl = Pl::EchoOk { echo };
+                            let txse = txse.clone();
+                            thread::spawn(move || {
+                                thread::sleep(ECHO_DELAY);
+                                let _ = txse.send(Evt::Int(Task::Reply(Box::new(resp))));
+                            });
+                        }
+                        #[cfg(not(feature = "slow-echo"))]
+                        resp.reply(Pl::EchoOk { echo }, &mut stdout)?;
                     }
                     Pl::Generate => {
-                        resp.body.pl = Pl::GenerateOk {
-                            id: Uuid::now_v7().to_string(),
+                        resp.reply(
+                            Pl::GenerateOk {
+                                id: id_gen.next_id(),
+                            },
+                            &mut stdout,
+                        )?;
+                    }
+                    Pl::Topology {
+                        topology: suggested,
+                    } => {
+                        topology.set_maelstrom(&id, &suggested);
+                        resp.reply(Pl::TopologyOk, &mut stdout)?;
+                    }
+                    Pl::Stats => {
+                        let metrics = Metrics {
+                            role: if id == topology.leader() {
+                                "leader"
+                            } else {
+                                "replica"
+                            }
+                            .to_string(),
+                            msgs_in,
+                            msgs_by_type: msgs_by_type
+                                .iter()
+                                .map(|(k, v)| (k.to_string(), *v))
+                                .collect(),
+                            messages: messages.len() as usize,
+                            seen: seen
+                                .iter()
+                                .map(|(k, v)| (k.clone(), v.len() as usize))
+                                .collect(),
+                            gossip_pending: pending.len(),
+                            kv_pending: kv_pending.len(),
+                            relays: relays.len(),
+                            buffered_txns: broadcaster.buffered,
+                            store_version: kvstore.version(),
+                            unknown: unknown_in,
+                            queued: backlog,
+                            gossip_shed,
+                            relay_retries,
                         };
-                        resp.send(&mut stdout)?;
+                        resp.reply(
+                            Pl::StatsOk {
+                                metrics: Box::new(metrics),
+                            },
+                            &mut stdout,
+                        )?;
                     }
-                    Pl::Topology { topology } => {
-                        default_neighbourhood = topology[&id].clone();
-                        resp.body.pl = Pl::TopologyOk;
-                        resp.send(&mut stdout)?;
+                    Pl::Subscribe => {
+                        // also how a client renews its lease
+                        subscribers.insert(resp.dst.clone(), Instant::now());
+                        resp.reply(Pl::SubscribeOk, &mut stdout)?;
+                    }
+                    Pl::Broadcast { msg, .. } if cfg!(feature = "or-set") => {
+                        if !orset.elements().contains(&msg) {
+                            notify(&mut stdout, &id, &mut subscribers, &HashSet::from([msg]))?;
+                        }
+                        orset.add(msg, id.clone(), orset_tag);
+                        orset_tag += 1;
+                        dedup.insert(&resp, Pl::BroadcastOk);
+                        resp.reply(Pl::BroadcastOk, &mut stdout)?;
+                    }
+                    Pl::Remove { msg } => {
+                        if cfg!(feature = "or-set") {
+                            orset.remove(msg);
+                            resp.reply(Pl::RemoveOk, &mut stdout)?;
+                        } else {
+                            resp.reply(
+                                Pl::Error {
+                                    code: 10,
+                                    text: "remove needs the or-set feature".to_string(),
+                                },
+                                &mut stdout,
+                            )?;
+                        }
+                    }
+                    Pl::GossipSet { set } => {
+                        orset.merge(set);
+                    }
+                    Pl::Broadcast { msg, trace } => {
+                        if trace && cfg!(feature = "trace") && !messages.contains(msg as u64) {
+                            traces.insert(msg, vec![id.clone()]);
+                            trace_paths.insert(msg, vec![vec![id.clone()]]);
+                        }
+                        if messages.insert(msg as u64) {
+                            notify(&mut stdout, &id, &mut subscribers, &HashSet::from([msg]))?;
+                        }
+                        dedup.insert(&resp, Pl::BroadcastOk);
+                        resp.reply(Pl::BroadcastOk, &mut stdout)?;
+                    }
+                    Pl::Gossip {
+                        mut msgs,
+                        deflated,
+                        cntr: peer_cntr,
+                        traces: peer_traces,
+                    } => {
+                        for (msg, mut path) in peer_traces {
+                            if traces.contains_key(&msg) {
+                                continue;
+                            }
+                            path.push(id.clone());
+                            let report = Msg {
+                                src: id.clone(),
+                                dst: path[0].clone(),
+                                body: Body {
+                                    pl: Pl::TracePath {
+                                        msg,
+                                        path: path.clone(),
+                                    },
+                                    msg_id: None,
+                                    in_reply_to: None,
+                                },
+                            };
+                            report.send(&mut stdout)?;
+                            traces.insert(msg, path);
+                        }
+                        if let Some(deflated) = deflated {
+                            msgs.extend(inflate(&deflated)?);
+                        }
+                        if let Some(peer_cntr) = peer_cntr {
+                            for name in counter.merge(&resp.dst, peer_cntr) {
+                                merged_at.insert(name, unix_ms());
+                            }
+                        }
+                        let new = msgs
+                            .iter()
+                            .filter(|x| !messages.contains(**x as u64))
+                            .copied()
+                            .collect();
+                        notify(&mut stdout, &id, &mut subscribers, &new)?;
+                        messages.extend(msgs.iter().map(|x| *x as u64));
+                        // a node missing from `node_ids` starts with nothing seen
+                        seen.entry(resp.dst.clone())
+                            .or_default()
+                            .extend(msgs.iter().map(|x| *x as u64));
+                        let gossip_id = resp.body.in_reply_to.unwrap();
+                        resp.reply(Pl::GossipOk { id: gossip_id }, &mut stdout)?;
+                    }
+                    Pl::GossipCntr { cntr } => {
+                        for name in counter.merge(&resp.dst, cntr) {
+                            merged_at.insert(name, unix_ms());
+                        }
+                    }
+                    // no range we'd have sent, drop the whole msg rather than trust any of it
+                    Pl::Digest { ranges, .. }
+                        if ranges.iter().any(|(lo, hi, _)| lo > hi || *hi > DIGEST_MAX) =>
+                    {
+                        eprintln!("digest from {} with a bad range, dropped", resp.dst);
+                    }
+                    // take what was sent, then answer each range that still differs with our
+                    // msgs in it when it's small and with its pieces' hashes otherwise
+                    Pl::Digest { ranges, msgs } => {
+                        let new = msgs
+                            .iter()
+                            .filter(|x| !messages.contains(**x as u64))
+                            .copied()
+                            .collect();
+                        notify(&mut stdout, &id, &mut subscribers, &new)?;
+                        messages.extend(new.iter().map(|x| *x as u64));
+                        seen.entry(resp.dst.clone())
+                            .or_default()
+                            .extend(msgs.iter().map(|x| *x as u64));
+                        let mut back = Vec::new();
+                        let mut push = Vec::new();
+                        for (lo, hi, hash) in ranges {
+                            let (mine, n) = range_hash(&messages, lo, hi);
+                            if mine == hash {
+                                continue;
+                            }
+                            if n <= DIGEST_LEAF_MSGS || hi - lo <= DIGEST_FANOUT {
+                                // the hash back gets us theirs if they have some we don't
+                                push.extend(msgs_within(&messages, lo, hi));
+                                back.push((lo, hi, mine));
+                            } else {
+                                for (a, b) in split_range(lo, hi) {
+                                    back.push((a, b, range_hash(&messages, a, b).0));
+                                }
+                            }
+                        }
+                        if !back.is_empty() {
+                            resp.reply(
+                                Pl::Digest {
+                                    ranges: back,
+                                    msgs: push,
+                                },
+                                &mut stdout,
+                            )?;
+                        }
+                    }
+                    Pl::TracePath { msg, path } => {
+                        trace_paths.entry(msg).or_default().push(path);
+                    }
+                    Pl::Trace { msg } => {
+                        resp.reply(
+                            Pl::TraceOk {
+                                paths: trace_paths.get(&msg).cloned().unwrap_or_default(),
+                            },
+                            &mut stdout,
+                        )?;
+                    }
+                    Pl::Heartbeat { heads } => {
+                        detector.heartbeat(&resp.dst);
+                        if resp.dst == topology.leader() {
+                            leader_heads = heads;
+                        }
+                    }
+                    Pl::Rejoin { extensions } => {
+                        reset_seen_for(&mut seen, &mut gossip_attempts, &resp.dst);
+                        cntr_sent.remove(&resp.dst);
+                        sent_offsets.remove(&resp.dst);
+                        peer_extensions.insert(resp.dst.clone(), extensions.into_iter().collect());
+                    }
+                    // a repeated ack finds nothing pending and the set extend is idempotent anyway,
+                    // so duplicates leave `seen` and `pending` as a single one did
+                    Pl::GossipOk { id } => {
+                        if let Some(gossip) = pending.remove(&id) {
+                            gossip_acked(gossip, &mut seen, &mut gossip_attempts);
+                        }
+                    }
+                    Pl::Read {
+                        key: None, name, ..
+                    } if cfg!(feature = "seq-kv") => {
+                        // seq-kv can serve a read from a stale state, a write of our own goes first
+                        // and the read is only sent once it's acked, so it sees at least that state
+                        let value = rpc_id;
+                        let kv_msg_id = send_kv(
+                            &mut stdout,
+                            &id,
+                            &mut rpc_id,
+                            Pl::Write {
+                                key: format!("{id}-sync"),
+                                value,
+                            },
+                        )?;
+                        resp.body.pl = Pl::ReadOk {
+                            msgs: None,
+                            value: None,
+                            behind: None,
+                            merged_at: None,
+                        };
+                        await_reply(
+                            &mut kv_pending,
+                            kv_msg_id,
+                            KvOp::Sync {
+                                client: resp,
+                                key: name.unwrap_or_else(|| CNTR_KEY.to_string()),
+                            },
+                        );
+                    }
+                    Pl::WriteOk => match in_reply_to.and_then(|x| kv_pending.remove(&x)) {
+                        Some(KvOp::Sync { client, key }) => {
+                            let kv_msg_id = send_kv(
+                                &mut stdout,
+                                &id,
+                                &mut rpc_id,
+                                Pl::Read {
+                                    key: Some(key),
+                                    msg_id: None,
+                                    name: None,
+                                    at_least: None,
+                                },
+                            )?;
+                            await_reply(&mut kv_pending, kv_msg_id, KvOp::Read { client });
+                        }
+                        _ => panic!("nope"),
+                    },
+                    Pl::ReadOk { value, .. } => {
+                        match in_reply_to.and_then(|x| kv_pending.remove(&x)) {
+                            Some(KvOp::Read { client }) => {
+                                client.reply(
+                                    Pl::ReadOk {
+                                        msgs: None,
+                                        value,
+                                        behind: None,
+                                        merged_at: None,
+                                    },
+                                    &mut stdout,
+                                )?;
+                            }
+                            Some(KvOp::Add {
+                                client,
+                                key,
+                                delta,
+                                tries,
+                            }) => {
+                                // only ever written by the cas below, never negative
+                                let from = value.unwrap_or_default() as usize;
+                                let kv_msg_id = send_kv(
+                                    &mut stdout,
+                                    &id,
+                                    &mut rpc_id,
+                                    Pl::Cas {
+                                        key: key.clone(),
+                                        from,
+                                        to: from + delta,
+                                        create_if_not_exists: true,
+                                    },
+                                )?;
+                                await_reply(
+                                    &mut kv_pending,
+                                    kv_msg_id,
+                                    KvOp::Add {
+                                        client,
+                                        key,
+                                        delta,
+                                        tries,
+                                    },
+                                );
+                            }
+                            _ => panic!("nope"),
+                        }
                     }
-                    Pl::Broadcast { msg } => {
-                        messages.insert(msg);
-                        resp.body.pl = Pl::BroadcastOk;
-                        resp.send(&mut stdout)?;
+                    Pl::CasOk => match in_reply_to.and_then(|x| kv_pending.remove(&x)) {
+                        // `add_ok` was already put in there
+                        Some(KvOp::Add { client, .. }) => {
+                            dedup.insert(&client, Pl::AddOk);
+                            client.send(&mut stdout)?;
+                        }
+                        _ => panic!("nope"),
+                    },
+                    Pl::Read {
+                        key: None,
+                        msg_id: None,
+                        name,
+                        at_least: Some(at_least),
+                    } if serves_counter
+                        && counter.value(name.as_deref().unwrap_or(CNTR_KEY)) < at_least =>
+                    {
+                        barrier_reads.push((
+                            resp,
+                            name.unwrap_or_else(|| CNTR_KEY.to_string()),
+                            at_least,
+                            Instant::now() + READ_BARRIER_TIMEOUT,
+                        ));
                     }
-                    Pl::Gossip { msgs } => {
-                        messages.extend(msgs.clone());
-                        seen.get_mut(&resp.dst).unwrap().extend(msgs.clone());
-                        resp.body.pl = Pl::GossipOk {
-                            id: resp.body.in_reply_to.unwrap(),
+                    // a register read, served from the txn store on a `kv` node
+                    Pl::Read { key: Some(key), .. } => {
+                        let pl = match key.parse() {
+                            _ if !cfg!(feature = "kv") => Pl::Error {
+                                code: 10,
+                                text: "not a kv node, read takes no key".to_string(),
+                            },
+                            Ok(key) => match kvstore.read(key) {
+                                Some(value) => Pl::ReadOk {
+                                    msgs: None,
+                                    value: Some(value.n),
+                                    behind: None,
+                                    merged_at: None,
+                                },
+                                None => Pl::Error {
+                                    code: 20,
+                                    text: format!("no key {key}"),
+                                },
+                            },
+                            Err(_) => Pl::Error {
+                                code: 12,
+                                text: format!("key {key:?} is not an integer"),
+                            },
                         };
-                        resp.send(&mut stdout)?;
+                        resp.reply(pl, &mut stdout)?;
                     }
-                    Pl::GossipCntr { cntr } => {
-                        // or default is not really needed since i did init all ot them with 0
-                        *cntrs.entry(resp.dst).or_default() = cntr;
+                    Pl::Read { name, .. } => {
+                        eprintln!("readp pl");
+                        let elements;
+                        let msgs = if cfg!(feature = "or-set") {
+                            elements = orset.elements().into_iter().map(|x| x as u64).collect();
+                            &elements
+                        } else {
+                            &messages
+                        };
+                        let read_ok = ReadOkRef {
+                            src: &resp.src,
+                            dst: &resp.dst,
+                            body: ReadOkBody {
+                                // some checkers want `"messages": []` rather than no field at all,
+                                // and a node serving both keeps the field so the read shows both sides
+                                msgs: if !serves_broadcast
+                                    || (msgs.is_empty()
+                                        && !cfg!(feature = "empty-messages")
+                                        && !serves_counter)
+                                {
+                                    None
+                                } else {
+                                    Some(msgs)
+                                },
+                                value: if serves_counter {
+                                    Some(counter.value(name.as_deref().unwrap_or(CNTR_KEY)) as i64)
+                                } else {
+                                    None
+                                },
+                                merged_at: merged_at
+                                    .get(name.as_deref().unwrap_or(CNTR_KEY))
+                                    .copied()
+                                    .filter(|_| serves_counter && cfg!(feature = "read-freshness")),
+                                msg_id: resp.body.msg_id,
+                                in_reply_to: resp.body.in_reply_to,
+                            },
+                        };
+                        write_out(&read_ok, &mut stdout)?;
                     }
-                    Pl::GossipOk { id } => {
-                        if let Some(pl) = pending.remove(&id) {
-                            seen.get_mut(&resp.dst).unwrap().extend(pl);
+                    // one already answered got its `add_ok` from `dedup` above, a retry while the first
+                    // is still in flight is dropped since that one will answer
+                    Pl::Add { delta, name } if cfg!(feature = "seq-kv") => {
+                        if dedup.start(&resp) {
+                            let key = name.unwrap_or_else(|| CNTR_KEY.to_string());
+                            let kv_msg_id = send_kv(
+                                &mut stdout,
+                                &id,
+                                &mut rpc_id,
+                                Pl::Read {
+                                    key: Some(key.clone()),
+                                    msg_id: None,
+                                    name: None,
+                                    at_least: None,
+                                },
+                            )?;
+                            resp.body.pl = Pl::AddOk;
+                            await_reply(
+                                &mut kv_pending,
+                                kv_msg_id,
+                                KvOp::Add {
+                                    client: resp,
+                                    key,
+                                    delta,
+                                    tries: 0,
+                                },
+                            );
                         }
                     }
-                    Pl::Read { key, msg_id } => {
-                        if key.is_some() || msg_id.is_some() {
-                            panic!("key is suposed to be recvd ONLY by lin-kv so nodes should never see this value");
+                    Pl::Add { delta, name } => {
+                        counter.add(name.unwrap_or_else(|| CNTR_KEY.to_string()), delta);
+                        dedup.insert(&resp, Pl::AddOk);
+                        resp.reply(Pl::AddOk, &mut stdout)?;
+                    }
+                    // replicated by the leader, not a client commit to forward back to it
+                    Pl::CommitOffsets { offsets } if resp.dst == topology.leader() => {
+                        for (key, offset) in offsets {
+                            store.commit(key, offset)?;
                         }
-                        eprintln!("readp pl");
-                        resp.body.pl = Pl::ReadOk {
-                            msgs: if messages.is_empty() {
-                                None
-                            } else {
-                                Some(messages.clone())
-                            },
-                            #[cfg(feature = "g-counter")]
-                            value: Some(cntrs.values().sum::<usize>() + cntr),
-                            #[cfg(not(feature = "g-counter"))]
-                            value: None,
-                        };
-                        resp.send(&mut stdout)?;
                     }
-                    Pl::Add { delta } => {
-                        cntr += delta;
-                        resp.body.pl = Pl::AddOk;
-                        resp.send(&mut stdout)?;
+                    // forwarding to a leader that is most likely down would just lose the write
+                    Pl::Send { .. } | Pl::CommitOffsets { .. }
+                        if id != topology.leader()
+                            && detector.suspicion_level(topology.leader()) > SUSPECT_PHI =>
+                    {
+                        resp.reply(
+                            Pl::Error {
+                                code: 11,
+                                text: format!("leader {} is suspected down", topology.leader()),
+                            },
+                            &mut stdout,
+                        )?;
                     }
                     // write, redirect to leader
-                    Pl::Send { key, msg } => {
+                    Pl::Send { key, msg, token } => {
                         // this will probably fail, if leader is partioned the writes would be lost
                         // either use lin-kv either send msgs of confirmations which might become slow
-                        if id == leader {
-                            let msgs = logs.entry(key.clone()).or_default();
-                            // naively relying on unique msgs
-                            if !msgs.contains(&msg) {
-                                msgs.push(msg);
+                        if id == topology.leader() {
+                            // equal values are legit separate appends, only a retry with the same
+                            // token gets the offset it was first written at
+                            let offset = match token
+                                .as_ref()
+                                .and_then(|x| send_tokens.get(&(key.clone(), x.clone())))
+                            {
+                                Some(offset) => *offset,
+                                None => store.append(&key, msg)?,
+                            };
+                            if let Some(token) = token {
+                                send_tokens.insert((key.clone(), token), offset);
                             }
-                            resp.body.pl = Pl::SendOk {
-                                offset: logs[&key].len() - 1,
+                            // the leader counts as one, so a majority is half of the cluster on top of it
+                            let needed = if cfg!(feature = "write-quorum") {
+                                topology.central().len().div_ceil(2)
+                            } else {
+                                0
                             };
-                            resp.send(&mut stdout)?; // respond to the req, but now spread the update
-                                                     // ok so just to validate, i will send all msgs, which is super slow
-                                                     // ideally:
-                                                     // 1. we send a vector
-                                                     // 2. leader have info on which last msg was
-                                                     //    seen, if not fallback to all
-                            for x in &central_neighbourhood {
+                            let repl_id = (needed > 0).then(|| {
+                                rpc_id += 1;
+                                rpc_id - 1
+                            });
+                            // respond to the req, but now spread the update
+                            // ok so just to validate, i will send all msgs, which is super slow
+                            // ideally:
+                            // 1. we send a vector
+                            // 2. leader have info on which last msg was
+                            //    seen, if not fallback to all
+                            for x in topology.central() {
                                 let msg_to_replica = Msg {
                                     src: id.clone(),
                                     dst: x.clone(),
                                     body: Body {
                                         pl: Pl::SendMany {
                                             key: key.clone(),
-                                            msgs: logs[&key].clone(),
+                                            // just appended to, but no panic if that ever moves
+                                            msgs: store
+                                                .read_from(&key, 0)
+                                                .unwrap_or_default()
+                                                .to_vec(),
                                         },
-                                        msg_id: None,
+                                        msg_id: repl_id,
                                         in_reply_to: None,
                                     },
                                 };
                                 msg_to_replica.send(&mut stdout)?;
                             }
+                            match repl_id {
+                                Some(repl_id) => {
+                                    if let Some(x) = resp.body.in_reply_to {
+                                        quorum_clients.insert((resp.dst.clone(), x));
+                                    }
+                                    await_reply(
+                                        &mut quorum_writes,
+                                        repl_id,
+                                        QuorumWrite {
+                                            client: resp,
+                                            offset,
+                                            acked: HashSet::new(),
+                                            needed,
+                                            first: Instant::now(),
+                                        },
+                                    )
+                                }
+                                None => {
+                                    dedup.insert(&resp, Pl::SendOk { offset });
+                                    resp.reply(Pl::SendOk { offset }, &mut stdout)?;
+                                }
+                            }
                         } else {
                             // this node is a replica and shouls send the write pl to leader
-                            resp.dst = leader.clone();
-                            resp.body.pl = Pl::Send { key, msg };
-                            resp.send(&mut stdout)?;
+                            forward(
+                                &mut stdout,
+                                &mut relays,
+                                &mut rpc_id,
+                                resp,
+                                topology.leader(),
+                                Pl::Send { key, msg, token },
+                            )?;
                         }
                     }
                     Pl::SendMany { key, msgs } => {
-                        let v = logs.get(&key);
+                        let v = store.read_from(&key, 0);
                         if v.is_some() && v.unwrap().len() > msgs.len() {
                         } else {
-                            logs.insert(key, msgs);
+                            store.replace(key, msgs)?;
+                        }
+                        // the leader is counting acks for this one
+                        if resp.body.in_reply_to.is_some() {
+                            resp.reply(Pl::SendManyOk, &mut stdout)?;
                         }
                     }
+                    // a late ack of a write already answered finds nothing
+                    Pl::SendManyOk => {
+                        let key = in_reply_to.unwrap_or_default();
+                        if let Some(write) = quorum_writes.get_mut(&key) {
+                            write.acked.insert(resp.dst);
+                            if write.acked.len() >= write.needed {
+                                let write = quorum_writes.remove(&key).unwrap();
+                                if let Some(x) = write.client.body.in_reply_to {
+                                    quorum_clients.remove(&(write.client.dst.clone(), x));
+                                }
+                                let offset = write.offset;
+                                dedup.insert(&write.client, Pl::SendOk { offset });
+                                write.client.reply(Pl::SendOk { offset }, &mut stdout)?;
+                            }
+                        }
+                    }
+                    // too stale here for this client, the leader has it all
+                    Pl::Poll {
+                        offsets,
+                        max_lag: Some(max_lag),
+                    } if id != topology.leader()
+                        && offsets.keys().any(|x| {
+                            let local = store.read_from(x, 0).map_or(0, |x| x.len());
+                            leader_heads.get(x).copied().unwrap_or_default() > local + max_lag
+                        }) =>
+                    {
+                        forward(
+                            &mut stdout,
+                            &mut relays,
+                            &mut rpc_id,
+                            resp,
+                            topology.leader(),
+                            Pl::Poll {
+                                offsets,
+                                max_lag: None,
+                            },
+                        )?;
+                    }
                     // read
-                    Pl::Poll { offsets } => {
-                        resp.body.pl = Pl::PollOk {
-                            msgs: offsets
-                                .into_iter()
-                                .filter_map(|(key, offset)| {
-                                    logs.get(&key).map(|msgs| {
-                                        (
-                                            key,
-                                            msgs.iter()
-                                                .enumerate()
-                                                .filter(|(i, _)| *i >= offset)
-                                                .map(|(i, msg)| (i, *msg))
-                                                .collect(),
-                                        )
-                                    })
-                                })
-                                .collect(),
-                        };
-                        resp.send(&mut stdout)?;
+                    Pl::Poll { offsets, .. } => {
+                        resp.reply(
+                            Pl::PollOk {
+                                msgs: poll_msgs(store.as_ref(), offsets),
+                            },
+                            &mut stdout,
+                        )?;
                     }
                     // redirect to leader
                     Pl::CommitOffsets { offsets } => {
-                        if id == leader {
+                        if id == topology.leader() {
                             for (key, offset) in &offsets {
-                                committed_offsets
-                                    .entry(key.to_string())
-                                    .and_modify(|x| *x = (*x).max(*offset))
-                                    .or_insert(*offset);
-                            }
-                            resp.body.pl = Pl::CommitOffsetsOk;
-                            resp.send(&mut stdout)?;
-                            for x in &central_neighbourhood {
-                                let msg_to_replic = Msg {
-                                    src: id.clone(),
-                                    dst: x.clone(),
-                                    body: Body {
-                                        pl: Pl::CommitOffsets {
-                                            offsets: offsets.clone(),
-                                        },
-                                        msg_id: None,
-                                        in_reply_to: None,
-                                    },
-                                };
-                                msg_to_replic.send(&mut stdout);
+                                store.commit(key.clone(), *offset)?;
                             }
+                            resp.reply(Pl::CommitOffsetsOk, &mut stdout)?;
+                            // only what moved since the last one we sent each replica
+                            let committed = store.list_committed(offsets.into_keys().collect());
+                            replicate_offsets(
+                                &mut stdout,
+                                &id,
+                                topology.central(),
+                                &committed,
+                                &mut sent_offsets,
+                                false,
+                            )?;
                         } else {
                             // this node is a replica and shouls send the write pl to leader
-                            resp.dst = leader.clone();
-                            resp.body.pl = Pl::CommitOffsets { offsets };
-                            resp.send(&mut stdout)?;
+                            forward(
+                                &mut stdout,
+                                &mut relays,
+                                &mut rpc_id,
+                                resp,
+                                topology.leader(),
+                                Pl::CommitOffsets { offsets },
+                            )?;
                         }
                     }
+                    // a commit may still be on its way from the leader, ask it instead of
+                    // answering with a stale local offset
+                    Pl::ListCommittedOffsets { keys }
+                        if cfg!(feature = "leader-offsets") && id != topology.leader() =>
+                    {
+                        forward(
+                            &mut stdout,
+                            &mut relays,
+                            &mut rpc_id,
+                            resp,
+                            topology.leader(),
+                            Pl::ListCommittedOffsets { keys },
+                        )?;
+                    }
                     // serve from replicas
                     Pl::ListCommittedOffsets { keys } => {
-                        resp.body.pl = Pl::ListCommittedOffsetsOk {
-                            offsets: keys
-                                .into_iter()
-                                .filter_map(|x| {
-                                    committed_offsets.get(&x).map(|offset| (x, *offset))
-                                })
-                                .collect(), // offsets: committed_offsets.iter().map(||{ }).collect(),
-                        };
-                        resp.send(&mut stdout)?;
+                        resp.reply(
+                            Pl::ListCommittedOffsetsOk {
+                                offsets: store.list_committed(keys),
+                            },
+                            &mut stdout,
+                        )?;
                     }
-                    Pl::AddOk
+                    // a type we don't know, one we only ever send (`write`, `cas`) or a reply nobody
+                    // here waits on, a stray one must not take the node and its queued msgs down
+                    pl @ (Pl::Unknown
+                    | Pl::Write { .. }
+                    | Pl::AddOk
                     | Pl::InitOk
                     | Pl::EchoOk { .. }
                     | Pl::GenerateOk { .. }
                     | Pl::BroadcastOk
-                    | Pl::ReadOk { .. }
+                    | Pl::RemoveOk
+                    | Pl::SubscribeOk
+                    | Pl::TraceOk { .. }
+                    | Pl::StatsOk { .. }
+                    | Pl::Notify { .. }
                     | Pl::TopologyOk
+                    | Pl::Cas { .. }
                     | Pl::SendOk { .. }
                     | Pl::TxnOk { .. }
                     | Pl::PollOk { .. }
-                    | Pl::CommitOffsetsOk { .. }
-                    | Pl::ListCommittedOffsetsOk { .. } => panic!("nope"),
+                    | Pl::CommitOffsetsOk
+                    | Pl::ListCommittedOffsetsOk { .. }) => {
+                        unknown_in += 1;
+                        let kind: &str = (&pl).into();
+                        match UNKNOWN_POLICY {
+                            UnknownPolicy::Reject => resp.reply(
+                                Pl::Error {
+                                    code: 10,
+                                    text: format!("unsupported msg type {kind}"),
+                                },
+                                &mut stdout,
+                            )?,
+                            UnknownPolicy::Ignore => {
+                                eprintln!("ignoring {kind} msg from {}", resp.dst)
+                            }
+                        }
+                    }
                 };
             }
             Evt::Int(task) => match task {
                 Task::SwitchPhase => {
                     state.switch_phase();
-                    if !state.receiving && broadcaster.has_all(state.cur_epoch) {
-                        let all_txns = broadcaster.get_all(state.cur_epoch);
-                        eprintln!("about to send all txns of len {}", all_txns.len());
-                        for txn in &all_txns {
-                            kvstore.apply_transaction(&txn.txn);
-                        }
-                        for node in &broadcaster.neighborhood {
+                    if !state.receiving {
+                        // our part of the epoch that just closed goes out whether the others sent theirs or not,
+                        // along with every older part a neighbour didn't ack, a lost one would stall it for good
+                        broadcaster.close(state.cur_epoch - 1, &id);
+                        for (node, epoch, txns) in broadcaster.unacked_parts() {
+                            eprintln!("sending {} txns of epoch {epoch} to {node}", txns.len());
                             let broadcast_msg = Msg {
                                 src: id.clone(),
-                                dst: node.clone(),
+                                dst: node,
                                 body: Body {
-                                    pl: Pl::BroadcastTxn {
-                                        txns: all_txns.clone(),
-                                    },
-                                    msg_id: Some(msg_id),
+                                    pl: Pl::BroadcastTxn { epoch, txns },
+                                    // acked by epoch, no id to match
+                                    msg_id: None,
                                     in_reply_to: None,
                                 },
                             };
                             broadcast_msg.send(&mut stdout)?;
-                            msg_id += 1;
                         }
                     }
+                    for txn in broadcaster.take_ready(state.cur_epoch, &id) {
+                        kvstore.apply_transaction(&txn.txn);
+                    }
+                }
+                Task::RetryRelays => {
+                    // no majority in time, the write stays in the leader log so it may well be there,
+                    // 0 is a timeout and says as much, a retry gets the same answer instead of appending again
+                    let expired: Vec<_> = quorum_writes
+                        .iter()
+                        .filter(|(_, x)| x.first.elapsed() > QUORUM_TIMEOUT)
+                        .map(|(k, _)| *k)
+                        .collect();
+                    for k in expired {
+                        let write = quorum_writes.remove(&k).unwrap();
+                        if let Some(x) = write.client.body.in_reply_to {
+                            quorum_clients.remove(&(write.client.dst.clone(), x));
+                        }
+                        let err = Pl::Error {
+                            code: 0,
+                            text: format!(
+                                "{} of the {} replica acks needed came in time",
+                                write.acked.len(),
+                                write.needed
+                            ),
+                        };
+                        dedup.insert(&write.client, err.clone());
+                        write.client.reply(err, &mut stdout)?;
+                    }
+                    let expired: Vec<_> = relays
+                        .iter()
+                        .filter(|(_, x)| x.first.elapsed() > RELAY_TIMEOUT)
+                        .map(|(k, _)| *k)
+                        .collect();
+                    for k in expired {
+                        let relay = relays.remove(&k).unwrap();
+                        relay.client.reply(
+                            Pl::Error {
+                                code: 0,
+                                text: format!("no answer from {}", relay.fwd.dst),
+                            },
+                            &mut stdout,
+                        )?;
+                    }
+                    for relay in relays.values_mut() {
+                        if relay.last.elapsed() > RELAY_RETRY {
+                            relay.fwd.clone().send(&mut stdout)?;
+                            relay.last = Instant::now();
+                            relay_retries += 1;
+                        }
+                    }
+                }
+                Task::Reply(msg) => {
+                    msg.send(&mut stdout)?;
+                }
+                Task::RetryAdd {
+                    client,
+                    key,
+                    delta,
+                    tries,
+                } => {
+                    let kv_msg_id = send_kv(
+                        &mut stdout,
+                        &id,
+                        &mut rpc_id,
+                        Pl::Read {
+                            key: Some(key.clone()),
+                            msg_id: None,
+                            name: None,
+                            at_least: None,
+                        },
+                    )?;
+                    await_reply(
+                        &mut kv_pending,
+                        kv_msg_id,
+                        KvOp::Add {
+                            client: *client,
+                            key,
+                            delta,
+                            tries,
+                        },
+                    );
+                }
+                Task::Flush => {
+                    stdout.flush()?;
+                    unflushed = 0;
+                }
+                // one neighbour a round, the whole msg space as the root range
+                Task::AntiEntropy => {
+                    entropy_round += 1;
+                    if let Some(host) = rotated(topology.mesh(), entropy_round).next() {
+                        let digest = Msg {
+                            src: id.clone(),
+                            dst: host.clone(),
+                            body: Body {
+                                pl: Pl::Digest {
+                                    ranges: vec![(
+                                        0,
+                                        DIGEST_MAX,
+                                        range_hash(&messages, 0, DIGEST_MAX).0,
+                                    )],
+                                    msgs: Vec::new(),
+                                },
+                                msg_id: None,
+                                in_reply_to: None,
+                            },
+                        };
+                        digest.send(&mut stdout)?;
+                    }
+                }
+                Task::Heartbeat => {
+                    heartbeat_rounds += 1;
+                    let heads = if id == topology.leader() {
+                        store.heads()
+                    } else {
+                        HashMap::new()
+                    };
+                    if id == topology.leader() && heartbeat_rounds % OFFSETS_FULL_SYNC_EVERY == 0 {
+                        let committed = store.list_committed(heads.keys().cloned().collect());
+                        replicate_offsets(
+                            &mut stdout,
+                            &id,
+                            topology.central(),
+                            &committed,
+                            &mut sent_offsets,
+                            true,
+                        )?;
+                    }
+                    for node in topology.mesh() {
+                        let heartbeat = Msg {
+                            src: id.clone(),
+                            dst: node.clone(),
+                            body: Body {
+                                pl: Pl::Heartbeat {
+                                    heads: heads.clone(),
+                                },
+                                msg_id: None,
+                                in_reply_to: None,
+                            },
+                        };
+                        heartbeat.send(&mut stdout)?;
+                    }
                 }
                 Task::CentralGossip => {
-                    for host in &central_neighbourhood {
-                        // one day check ever growing when particioned
-                        let unseen_by_host: HashSet<_> =
-                            messages.difference(&seen[host]).copied().collect();
+                    // lost acks would otherwise pile up in here, once a second is plenty to sweep
+                    pending.retain(|_, x| x.sent.elapsed() < GOSSIP_ACK_TIMEOUT);
+                    central_round += 1;
+                    for host in rotated(topology.central(), central_round) {
+                        let unseen_by_host =
+                            unseen_by(host, &messages, &seen, &mut gossip_attempts);
                         if !unseen_by_host.is_empty() {
                             let resp = Msg {
                                 src: id.clone(),
                                 dst: host.clone(),
                                 body: Body {
-                                    pl: Pl::Gossip {
-                                        msgs: unseen_by_host.clone(),
-                                    },
-                                    msg_id: Some(msg_id),
+                                    pl: gossip_pl(
+                                        unseen_by_host.clone(),
+                                        None,
+                                        peer_extensions
+                                            .get(host)
+                                            .is_some_and(|x| x.contains("deflate")),
+                                        traces_for(&traces, &unseen_by_host),
+                                    )?,
+                                    msg_id: Some(rpc_id),
                                     in_reply_to: None,
                                 },
                             };
                             resp.send(&mut stdout)?;
-                            pending.insert(msg_id, unseen_by_host.clone());
-                            msg_id += 1;
+                            await_reply(
+                                &mut pending,
+                                rpc_id,
+                                PendingGossip {
+                                    dst: host.clone(),
+                                    msgs: unseen_by_host.clone(),
+                                    sent: Instant::now(),
+                                },
+                            );
+                            rpc_id += 1;
                         }
                     }
                 }
+                // nothing is lost, the next round sends whatever this one would have
+                Task::MeshGossip
+                    if queued.load(atomic::Ordering::Relaxed) > GOSSIP_SHED_QUEUED
+                        && !shed_last_round =>
+                {
+                    shed_last_round = true;
+                    gossip_shed += 1;
+                }
+                Task::MeshGossip if cfg!(feature = "or-set") => {
+                    shed_last_round = false;
+                    mesh_round += 1;
+                    for host in rotated(topology.mesh(), mesh_round) {
+                        let resp = Msg {
+                            src: id.clone(),
+                            dst: host.clone(),
+                            body: Body {
+                                pl: Pl::GossipSet { set: orset.clone() },
+                                msg_id: None,
+                                in_reply_to: None,
+                            },
+                        };
+                        resp.send(&mut stdout)?;
+                    }
+                }
                 Task::MeshGossip => {
-                    for host in &mesh_neighbourhood {
-                        let unseen_by_host: HashSet<_> =
-                            messages.difference(&seen[host]).copied().collect();
-                        if !unseen_by_host.is_empty() {
+                    let piggyback = cfg!(feature = "piggyback");
+                    shed_last_round = false;
+                    mesh_round += 1;
+                    for host in rotated(topology.mesh(), mesh_round) {
+                        let unseen_by_host =
+                            unseen_by(host, &messages, &seen, &mut gossip_attempts);
+                        // counters have to go out every round, even with nothing new to broadcast
+                        if !unseen_by_host.is_empty() || piggyback {
                             let resp = Msg {
                                 src: id.clone(),
                                 dst: host.clone(),
                                 body: Body {
-                                    pl: Pl::Gossip {
-                                        msgs: unseen_by_host.clone(),
-                                    },
-                                    msg_id: Some(msg_id),
+                                    pl: gossip_pl(
+                                        unseen_by_host.clone(),
+                                        piggyback.then(|| counter.local().clone()),
+                                        peer_extensions
+                                            .get(host)
+                                            .is_some_and(|x| x.contains("deflate")),
+                                        traces_for(&traces, &unseen_by_host),
+                                    )?,
+                                    msg_id: Some(rpc_id),
                                     in_reply_to: None,
                                 },
                             };
                             resp.send(&mut stdout)?;
-                            pending.insert(msg_id, unseen_by_host.clone());
-                            msg_id += 1;
+                            await_reply(
+                                &mut pending,
+                                rpc_id,
+                                PendingGossip {
+                                    dst: host.clone(),
+                                    msgs: unseen_by_host.clone(),
+                                    sent: Instant::now(),
+                                },
+                            );
+                            rpc_id += 1;
                         }
                     }
                 }
                 Task::GossipCntr => {
-                    for node_to_contact in &mesh_neighbourhood {
+                    cntr_rounds += 1;
+                    let full_sync = cntr_rounds % CNTR_FULL_SYNC_EVERY == 0;
+                    for node_to_contact in rotated(topology.mesh(), cntr_rounds) {
+                        let sent = cntr_sent.entry(node_to_contact.clone()).or_default();
+                        // only the counters that moved since the last one to this node
+                        let cntr: HashMap<_, _> = counter
+                            .local()
+                            .iter()
+                            .filter(|(k, v)| full_sync || sent.get(*k) != Some(v))
+                            .map(|(k, v)| (k.clone(), *v))
+                            .collect();
+                        if cntr.is_empty() {
+                            continue;
+                        }
+                        sent.extend(cntr.clone());
                         let resp = Msg {
                             src: id.clone(),
                             dst: node_to_contact.clone(),
                             body: Body {
                                 pl: Pl::GossipCntr { cntr },
-                                msg_id: Some(msg_id),
+                                msg_id: None,
                                 in_reply_to: None,
                             },
                         };
                         resp.send(&mut stdout)?;
-                        msg_id += 1;
                     }
                 }
             },
+            Evt::Eof => break,
+        }
+    }
+    store.on_shutdown()?;
+    stdout.flush()?;
+    // the local part is the sum of the deltas this node got, a final read below it means lost updates
+    if cfg!(feature = "g-counter") {
+        for (name, added) in counter.local() {
+            eprintln!(
+                "{name}: added locally {added}, final read {}",
+                counter.value(name)
+            );
         }
     }
-    // join
+    jhc.join().expect("stdin reader panicked")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn val_goes_back_out_as_it_came_in() {
+        for raw in [r#"["w",1,9007199254740993]"#, r#"["w",1,"42"]"#] {
+            let op: TxnOp = serde_json::from_str(raw).unwrap();
+            assert_eq!(serde_json::to_string(&op).unwrap(), raw);
+        }
+        let op: TxnOp = serde_json::from_str(r#"["w",1,"9007199254740993"]"#).unwrap();
+        assert_eq!(op.2.unwrap().n, 9007199254740993);
+        assert!(serde_json::from_str::<TxnOp>(r#"["w",1,"x"]"#).is_err());
+    }
+
+    #[test]
+    fn read_gets_the_form_that_was_written() {
+        let mut store = KVStore::new();
+        let txn: Vec<TxnOp> = serde_json::from_str(r#"[["w",1,"42"],["r",1,null]]"#).unwrap();
+        let (result, prior) = store.apply_transaction(&txn);
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"[["w",1,"42"],["r",1,"42"]]"#
+        );
+        assert!(prior.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn prior_holds_what_each_write_overwrote() {
+        let mut store = KVStore::new();
+        let txn = |raw: &str| -> Vec<TxnOp> { serde_json::from_str(raw).unwrap() };
+        store.apply_transaction(&txn(r#"[["w",1,5]]"#));
+        let (result, prior) = store.apply_transaction(&txn(
+            r#"[["w",1,6],["r",1,null],["w",2,"7"],["w",1,8],["w",2,9]]"#,
+        ));
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"[["w",1,6],["r",1,6],["w",2,"7"],["w",1,8],["w",2,9]]"#
+        );
+        // null for the read and the fresh key, a write within the same txn counts as overwritten
+        assert_eq!(
+            serde_json::to_string(&prior).unwrap(),
+            r#"[5,null,null,6,"7"]"#
+        );
+    }
+
+    #[derive(Debug, Clone)]
+    enum BroadcasterOp {
+        Init(Vec<String>),
+        Push(usize, String, Vec<usize>),
+    }
+
+    fn broadcaster_op() -> impl proptest::strategy::Strategy<Value = BroadcasterOp> {
+        use proptest::prelude::*;
+        let node = (0..4usize).prop_map(|x| format!("n{x}"));
+        prop_oneof![
+            proptest::collection::vec(node.clone(), 0..4).prop_map(BroadcasterOp::Init),
+            (0..3usize, node, proptest::collection::vec(0..5usize, 0..4))
+                .prop_map(|(epoch, node, seqs)| BroadcasterOp::Push(epoch, node, seqs)),
+        ]
+    }
+
+    proptest::proptest! {
+        // has_all is every neighbour having pushed for the epoch, get_all has each pushed
+        // (node, seq) once however often it was pushed
+        #[test]
+        fn has_all_and_get_all_agree(ops in proptest::collection::vec(broadcaster_op(), 0..40)) {
+            let mut broadcaster = Broadcaster::new();
+            let mut pushed: HashMap<usize, HashMap<String, HashSet<usize>>> = HashMap::new();
+            for op in ops {
+                match op {
+                    BroadcasterOp::Init(nodes) => broadcaster.init(nodes),
+                    BroadcasterOp::Push(epoch, node, seqs) => {
+                        let txns = seqs
+                            .iter()
+                            .map(|x| SeqTxn { seq: *x, txn: Vec::new() })
+                            .collect();
+                        broadcaster.push(epoch, node.clone(), txns);
+                        pushed.entry(epoch).or_default().entry(node).or_default().extend(seqs);
+                    }
+                }
+                for epoch in 0..3 {
+                    let senders = pushed.get(&epoch);
+                    let expected = broadcaster
+                        .neighborhood
+                        .iter()
+                        .all(|x| senders.is_some_and(|y| y.contains_key(x)));
+                    proptest::prop_assert_eq!(broadcaster.has_all(epoch), expected);
+                    let n: usize = senders.map_or(0, |x| x.values().map(HashSet::len).sum());
+                    proptest::prop_assert_eq!(broadcaster.get_all(epoch).len(), n);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_neighbourhood_has_all() {
+        let mut broadcaster = Broadcaster::new();
+        assert!(broadcaster.has_all(0));
+        broadcaster.init(Vec::new());
+        assert!(broadcaster.has_all(7));
+        assert!(broadcaster.get_all(7).is_empty());
+    }
+
+    fn seq_txns(seqs: &[usize]) -> Vec<SeqTxn> {
+        seqs.iter()
+            .map(|x| SeqTxn {
+                seq: *x,
+                txn: Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn early_epoch_waits_for_the_node_to_get_there() {
+        let mut broadcaster = Broadcaster::new();
+        broadcaster.init(vec!["n1".to_string(), "n2".to_string()]);
+        broadcaster.push(0, "n0".to_string(), seq_txns(&[0]));
+        broadcaster.push(0, "n1".to_string(), seq_txns(&[0]));
+        broadcaster.push(0, "n2".to_string(), Vec::new());
+        // epoch 2 from both while we only closed epoch 0
+        broadcaster.push(2, "n1".to_string(), seq_txns(&[5]));
+        broadcaster.push(2, "n2".to_string(), seq_txns(&[6]));
+        let ready: Vec<_> = broadcaster
+            .take_ready(1, "n0")
+            .iter()
+            .map(|x| x.seq)
+            .collect();
+        assert_eq!(ready, [0], "epoch 0 without our own part");
+        assert!(broadcaster.take_ready(1, "n0").is_empty());
+        // epoch 1 is still missing n2, so epoch 2 waits behind it
+        broadcaster.push(1, "n1".to_string(), seq_txns(&[3]));
+        assert!(broadcaster.take_ready(3, "n0").is_empty());
+        broadcaster.push(1, "n2".to_string(), seq_txns(&[4]));
+        let mut ready: Vec<_> = broadcaster
+            .take_ready(3, "n0")
+            .iter()
+            .map(|x| x.seq)
+            .collect();
+        ready[..2].sort();
+        ready[2..].sort();
+        assert_eq!(ready, [3, 4, 5, 6]);
+        // a repeat of an applied epoch is not applied again
+        broadcaster.push(1, "n1".to_string(), seq_txns(&[3]));
+        assert!(broadcaster.take_ready(3, "n0").is_empty());
+        assert!(broadcaster.broadcast_nodes.is_empty());
+    }
+
+    #[test]
+    fn switch_phase_toggles_and_moves_the_epoch_on_sending() {
+        let mut state = State::new();
+        assert!(state.receiving);
+        assert_eq!(state.cur_epoch, 0);
+        state.next_txn_id();
+        let phases: Vec<_> = (0..4)
+            .map(|_| {
+                state.switch_phase();
+                (state.receiving, state.cur_epoch)
+            })
+            .collect();
+        assert_eq!(phases, [(false, 1), (true, 1), (false, 2), (true, 2)]);
+    }
+
+    fn node_ids(n: usize) -> Vec<String> {
+        (0..n).map(|x| format!("n{x}")).collect()
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let all = Topology::new("n0", &node_ids(5));
+        let again = Topology::new("n0", &node_ids(5));
+        let without_n3: Vec<_> = node_ids(5).into_iter().filter(|x| x != "n3").collect();
+        let fewer = Topology::new("n0", &without_n3);
+        let mut owners = HashSet::new();
+        for key in (0..1000).map(|x| format!("k{x}")) {
+            let owner = all.owner_of(&key);
+            assert_eq!(owner, again.owner_of(&key));
+            owners.insert(owner.to_string());
+            if owner != "n3" {
+                assert_eq!(owner, fewer.owner_of(&key), "{key} moved off {owner}");
+            } else {
+                assert_ne!(fewer.owner_of(&key), "n3");
+            }
+        }
+        assert_eq!(owners.len(), 5);
+        let owner = all.owner_of("k0");
+        assert!(Topology::new(owner, &node_ids(5)).is_owner("k0"));
+        assert_eq!(all.is_owner("k0"), owner == "n0");
+    }
+
+    #[test]
+    fn node_ids_sort_by_number() {
+        let mut ids = node_ids(13);
+        ids.reverse();
+        ids.push("lin-kv".to_string());
+        ids.push("c1".to_string());
+        ids.push("nx".to_string());
+        ids.sort_by(|a, b| cmp_node_ids(a, b));
+        let mut expected = node_ids(13);
+        expected.extend(["c1", "lin-kv", "nx"].map(String::from));
+        assert_eq!(ids, expected);
+        assert_eq!(cmp_node_ids("n2", "n10"), Ordering::Less);
+        assert_eq!(cmp_node_ids("n12", "n12"), Ordering::Equal);
+    }
+
+    #[test]
+    fn concurrent_add_wins_over_remove() {
+        let mut a = OrSet::default();
+        a.add(1, "n0".to_string(), 0);
+        let mut b = a.clone();
+        // n0 removes 1 while n1 adds it again without having seen the remove
+        a.remove(1);
+        b.add(1, "n1".to_string(), 0);
+        assert!(a.elements().is_empty());
+        let mut ab = a.clone();
+        ab.merge(b.clone());
+        b.merge(a);
+        assert_eq!(ab.elements(), HashSet::from([1]));
+        assert_eq!(b.elements(), HashSet::from([1]));
+        // a remove that saw both adds takes it out for good
+        ab.remove(1);
+        b.merge(ab);
+        assert!(b.elements().is_empty());
+    }
+
+    #[test]
+    fn failure_detector_suspects_a_node_gone_quiet() {
+        let mut detector = FailureDetector::new();
+        assert_eq!(detector.suspicion_level("n1"), 0.0);
+        detector.heartbeat("n1");
+        assert_eq!(
+            detector.suspicion_level("n1"),
+            0.0,
+            "no gap to compare with yet"
+        );
+        // heartbeats every 100ms, the window only keeps the last ones
+        detector.gaps.insert(
+            "n1".to_string(),
+            VecDeque::from(vec![100.0; HEARTBEAT_WINDOW]),
+        );
+        let ago = |ms| Instant::now() - Duration::from_millis(ms);
+        detector.last.insert("n1".to_string(), ago(50));
+        assert!(detector.suspicion_level("n1") < 1.0);
+        detector.last.insert("n1".to_string(), ago(130));
+        let late = detector.suspicion_level("n1");
+        assert!(late > 1.0 && late < SUSPECT_PHI, "{late}");
+        detector.last.insert("n1".to_string(), ago(1000));
+        assert!(detector.suspicion_level("n1") > SUSPECT_PHI);
+        detector.heartbeat("n1");
+        assert_eq!(detector.gaps["n1"].len(), HEARTBEAT_WINDOW);
+        assert!(detector.suspicion_level("n1") < SUSPECT_PHI);
+    }
+
+    // run against every `LogStore`, they must all answer a poll alike
+    fn poll_msgs_in_offset_order_and_capped(store: &mut dyn LogStore) {
+        for msg in [7, 8, 9] {
+            store.append("a", msg).unwrap();
+        }
+        store.append("b", 1).unwrap();
+        let polled = poll_msgs(
+            store,
+            HashMap::from([
+                ("a".to_string(), 1),
+                ("b".to_string(), 5),
+                ("c".to_string(), 0),
+            ]),
+        );
+        assert_eq!(polled["a"], [(1, 8), (2, 9)]);
+        assert!(polled["b"].is_empty(), "past the end");
+        assert!(!polled.contains_key("c"), "never written");
+        // a log bigger than a poll is cut but still answers from the offset asked for
+        store
+            .replace("big".to_string(), (0..100_000).collect())
+            .unwrap();
+        let polled = poll_msgs(store, HashMap::from([("big".to_string(), 10)]));
+        let big = &polled["big"];
+        assert!(big.len() < 100_000 - 10);
+        assert!(serde_json::to_string(big).unwrap().len() <= POLL_MAX_BYTES + 2);
+        assert_eq!(big[0], (10, 10));
+        assert!(big.windows(2).all(|x| x[1].0 == x[0].0 + 1));
+    }
+
+    #[test]
+    fn poll_msgs_in_offset_order_and_capped_for_every_store() {
+        poll_msgs_in_offset_order_and_capped(&mut MemLog::default());
+        let path = std::env::temp_dir().join(format!("wal-poll-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        poll_msgs_in_offset_order_and_capped(&mut WalLog::open(path).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn wal_open_cuts_a_torn_tail() {
+        let path = std::env::temp_dir().join(format!("wal-torn-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(
+            path,
+            "{\"op\":\"append\",\"key\":\"k\",\"msg\":5}\n{\"op\":\"commit\",\"key\":\"k\",\"offset\":0}\n{\"op\":\"app",
+        )
+        .unwrap();
+        let (mut wal, records) = Wal::open(path).unwrap();
+        assert_eq!(records.len(), 2);
+        wal.append(&WalRecord::Append {
+            key: "k".to_string(),
+            msg: 6,
+        })
+        .unwrap();
+        drop(wal);
+        let (_, records) = Wal::open(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            format!("{records:?}"),
+            r#"[Append { key: "k", msg: 5 }, Commit { key: "k", offset: 0 }, Append { key: "k", msg: 6 }]"#
+        );
+    }
+
+    // the check only runs in debug builds
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "replays to a different state")]
+    fn wal_check_catches_a_file_changed_under_it() {
+        let path = std::env::temp_dir().join(format!("wal-check-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let mut store = WalLog::open(path).unwrap();
+        store.append("k", 5).unwrap();
+        // same length so the writes after it still land right behind it
+        let edited = fs::read_to_string(path)
+            .unwrap()
+            .replace("\"msg\":5", "\"msg\":6");
+        fs::write(path, edited).unwrap();
+        for x in 0..WAL_CHECK_EVERY {
+            store.append("k", x).unwrap();
+        }
+    }
+
+    #[test]
+    fn split_range_covers_the_range_in_fanout_pieces() {
+        for (lo, hi) in [(0, DIGEST_MAX), (3, 20), (100, 117), (0, 1000)] {
+            let pieces = split_range(lo, hi);
+            assert!(pieces.len() <= DIGEST_FANOUT, "{lo}..{hi}");
+            assert_eq!(pieces.first().unwrap().0, lo);
+            assert_eq!(pieces.last().unwrap().1, hi);
+            assert!(pieces
+                .windows(2)
+                .all(|x| x[0].1 == x[1].0 && x[0].0 < x[0].1));
+        }
+    }
+
+    #[test]
+    fn range_hash_sums_so_order_and_splits_dont_matter() {
+        let msgs: RoaringTreemap = [1, 5, 40, 41, 900].into_iter().collect();
+        let (whole, n) = range_hash(&msgs, 0, DIGEST_MAX);
+        assert_eq!(n, 5);
+        assert!(whole < 1 << 53);
+        assert_eq!(range_hash(&msgs, 0, 41).1, 3, "hi is left out");
+        let pieces = split_range(0, 1000);
+        let (sum, count) = pieces
+            .iter()
+            .map(|(a, b)| range_hash(&msgs, *a, *b))
+            .fold((0u64, 0), |(h, n), (x, m)| {
+                ((h + x) & ((1 << 53) - 1), n + m)
+            });
+        assert_eq!((sum, count), (whole, 5));
+        let mut fewer = msgs.clone();
+        fewer.remove(40);
+        assert_ne!(range_hash(&fewer, 0, DIGEST_MAX).0, whole);
+        assert_eq!(range_hash(&msgs, 7, 7), (0, 0));
+    }
+
+    #[test]
+    fn msgs_within_matches_a_filter_over_every_msg() {
+        // across the 2^32 boundary the treemap splits its bitmaps at
+        let all = [
+            0,
+            3,
+            4,
+            1 << 20,
+            (1 << 32) - 1,
+            1 << 32,
+            (1 << 32) + 9,
+            1 << 40,
+        ];
+        let msgs: RoaringTreemap = all.into_iter().collect();
+        for (lo, hi) in [
+            (0, DIGEST_MAX),
+            (3, 4),
+            (4, 1 << 32),
+            (5, 1 << 20),
+            ((1 << 32) - 1, (1 << 32) + 10),
+            (1 << 41, DIGEST_MAX),
+        ] {
+            let want: Vec<_> = all
+                .iter()
+                .map(|x| *x as usize)
+                .filter(|x| (lo..hi).contains(x))
+                .collect();
+            assert_eq!(
+                msgs_within(&msgs, lo, hi).collect::<Vec<_>>(),
+                want,
+                "{lo}..{hi}"
+            );
+        }
+    }
+
+    #[test]
+    fn gcounter_merge_keeps_the_max_per_node() {
+        let mut counter = GCounter::default();
+        counter.add("a".to_string(), 2);
+        let gossip = |x: &[(&str, usize)]| x.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+        assert_eq!(counter.merge("n1", gossip(&[("a", 3), ("b", 1)])).len(), 2);
+        assert_eq!(counter.value("a"), 5);
+        // an old gossip arriving late or twice doesn't lower or double anything
+        assert!(counter
+            .merge("n1", gossip(&[("a", 1), ("b", 1)]))
+            .is_empty());
+        assert_eq!(counter.value("a"), 5);
+        assert_eq!(counter.merge("n1", gossip(&[("a", 4)])), ["a"]);
+        counter.merge("n2", gossip(&[("a", 10)]));
+        assert_eq!(counter.value("a"), 2 + 4 + 10);
+        assert_eq!(counter.value("b"), 1);
+        assert_eq!(counter.value("c"), 0);
+        // what we gossip is only our own adds
+        assert_eq!(counter.local()["a"], 2);
+    }
+
+    // random adds on every node while a third of the gossip gets lost, once it heals
+    // and every node gossiped once more they all read the sum of every delta
+    #[test]
+    fn counters_converge_on_the_total_after_loss_heals() {
+        let ids = node_ids(3);
+        let mut counters: Vec<GCounter> = ids.iter().map(|_| GCounter::default()).collect();
+        let mut rng = 0x5eed;
+        let mut total = 0;
+        let gossip = |counters: &mut [GCounter], lossy: bool, rng: &mut u64| {
+            for from in 0..ids.len() {
+                let cntr = counters[from].local().clone();
+                for to in (0..ids.len()).filter(|x| *x != from) {
+                    if lossy && xorshift(rng).is_multiple_of(3) {
+                        continue;
+                    }
+                    counters[to].merge(&ids[from], cntr.clone());
+                }
+            }
+        };
+        for round in 0..200_usize {
+            let node = xorshift(&mut rng) as usize % ids.len();
+            let delta = xorshift(&mut rng) as usize % 10;
+            counters[node].add(CNTR_KEY.to_string(), delta);
+            total += delta;
+            if round.is_multiple_of(5) {
+                gossip(&mut counters, true, &mut rng);
+            }
+        }
+        assert!(counters.iter().any(|x| x.value(CNTR_KEY) < total));
+        gossip(&mut counters, false, &mut rng);
+        for (id, counter) in ids.iter().zip(&counters) {
+            assert_eq!(counter.value(CNTR_KEY), total, "{id} is off");
+        }
+    }
+
+    #[test]
+    fn jitter_stays_in_its_bounds() {
+        let every = Duration::from_millis(300);
+        let mut rng = 1;
+        assert_eq!(Jitter::Off.next(every, every, &mut rng), every);
+        let full: Vec<_> = (0..1000)
+            .map(|_| Jitter::Full.next(every, every, &mut rng))
+            .collect();
+        assert!(full.iter().all(|x| *x < every * 2));
+        let mean = full.iter().sum::<Duration>() / 1000;
+        assert!(mean > every * 9 / 10 && mean < every * 11 / 10, "{mean:?}");
+        let mut sleep = every;
+        for _ in 0..1000 {
+            let next = Jitter::Decorrelated.next(every, sleep, &mut rng);
+            assert!(next >= every / 2 && next <= every * 2, "{next:?}");
+            assert!(next <= sleep * 3);
+            sleep = next;
+        }
+        // no span to pick from
+        assert_eq!(
+            Jitter::Full.next(Duration::ZERO, Duration::ZERO, &mut rng),
+            Duration::ZERO
+        );
+    }
+
+    fn client_req(client: &str, msg_id: usize) -> Msg {
+        let req = Msg {
+            src: client.to_string(),
+            dst: "n0".to_string(),
+            body: Body {
+                pl: Pl::Add {
+                    delta: 1,
+                    name: None,
+                },
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+            },
+        };
+        req.into_resp(&mut 0)
+    }
+
+    #[test]
+    fn dedup_answers_a_retry_and_forgets_the_oldest() {
+        let mut dedup = DedupCache::new();
+        dedup.insert(&client_req("c1", 1), Pl::AddOk);
+        assert!(matches!(dedup.get(&client_req("c1", 1)), Some(Pl::AddOk)));
+        assert!(dedup.get(&client_req("c1", 2)).is_none());
+        assert!(dedup.get(&client_req("c2", 1)).is_none());
+        // the same request again keeps its first place in line
+        dedup.insert(&client_req("c1", 1), Pl::AddOk);
+        for x in 2..=DEDUP_CAPACITY {
+            dedup.insert(&client_req("c1", x), Pl::AddOk);
+        }
+        assert!(dedup.get(&client_req("c1", 1)).is_some());
+        dedup.insert(&client_req("c2", 1), Pl::AddOk);
+        assert!(dedup.get(&client_req("c1", 1)).is_none());
+        assert!(dedup.get(&client_req("c1", 2)).is_some());
+        assert_eq!(dedup.replies.len(), DEDUP_CAPACITY);
+        // nothing to key a msg without a msg_id on
+        let mut resp = client_req("c3", 1);
+        resp.body.in_reply_to = None;
+        dedup.insert(&resp, Pl::AddOk);
+        assert!(dedup.get(&resp).is_none());
+    }
+
+    #[test]
+    fn retried_add_increments_exactly_once() {
+        let mut dedup = DedupCache::new();
+        // what the seq-kv `add` arms do, a cached reply first, then a cas only for a new request
+        let add = |dedup: &mut DedupCache, resp: &Msg| {
+            if dedup.get(resp).is_some() {
+                "cached"
+            } else if dedup.start(resp) {
+                "incremented"
+            } else {
+                "dropped"
+            }
+        };
+        let first = client_req("c1", 5);
+        assert_eq!(add(&mut dedup, &first), "incremented");
+        // retried while the cas is still in flight
+        assert_eq!(add(&mut dedup, &first), "dropped");
+        // `cas_ok`
+        dedup.insert(&first, Pl::AddOk);
+        assert_eq!(add(&mut dedup, &first), "cached");
+        assert_eq!(add(&mut dedup, &client_req("c1", 6)), "incremented");
+        // without a msg_id every one counts, and its `cas_ok` has nothing to put in the cache
+        let mut resp = client_req("c2", 1);
+        resp.body.in_reply_to = None;
+        assert_eq!(add(&mut dedup, &resp), "incremented");
+        assert_eq!(add(&mut dedup, &resp), "incremented");
+        dedup.insert(&resp, Pl::AddOk);
+        assert_eq!(dedup.replies.len(), 2);
+    }
+
+    #[test]
+    fn own_part_is_resent_until_every_neighbour_acks() {
+        let mut broadcaster = Broadcaster::new();
+        broadcaster.init(vec!["n1".to_string(), "n2".to_string()]);
+        broadcaster.push(0, "n0".to_string(), seq_txns(&[0, 1]));
+        broadcaster.close(0, "n0");
+        broadcaster.close(1, "n0");
+        let parts = |x: &Broadcaster| {
+            let mut parts: Vec<_> = x
+                .unacked_parts()
+                .into_iter()
+                .map(|(node, epoch, txns)| (node, epoch, txns.len()))
+                .collect();
+            parts.sort();
+            parts
+        };
+        let n = |x: &str| x.to_string();
+        assert_eq!(
+            parts(&broadcaster),
+            [
+                (n("n1"), 0, 2),
+                (n("n1"), 1, 0),
+                (n("n2"), 0, 2),
+                (n("n2"), 1, 0)
+            ]
+        );
+        broadcaster.ack("n1", 0);
+        broadcaster.ack("n1", 0);
+        broadcaster.ack("n2", 1);
+        assert_eq!(parts(&broadcaster), [(n("n1"), 1, 0), (n("n2"), 0, 2)]);
+        broadcaster.ack("n2", 0);
+        broadcaster.ack("n1", 1);
+        assert!(broadcaster.unacked.is_empty());
+        // our part left `broadcast_nodes` so it isn't applied a second time
+        assert!(broadcaster.get_all(0).is_empty());
+    }
+
+    #[test]
+    fn txns_flooded_past_the_buffer_get_11_until_it_drains() {
+        let mut broadcaster = Broadcaster::new();
+        broadcaster.init(vec!["n1".to_string(), "n2".to_string()]);
+        // n2 never sends nor acks, so no epoch gets applied and our parts stay unacked
+        let mut seq = 0;
+        let mut rejected = 0;
+        for epoch in 0..4 {
+            for _ in 0..MAX_BUFFERED_TXNS {
+                if let Some(err) = txn_error(broadcaster.buffered, 1) {
+                    assert!(matches!(err, Pl::Error { code: 11, .. }));
+                    rejected += 1;
+                    continue;
+                }
+                broadcaster.push(epoch, "n0".to_string(), seq_txns(&[seq]));
+                seq += 1;
+            }
+            broadcaster.push(epoch, "n1".to_string(), seq_txns(&[seq]));
+            seq += 1;
+            broadcaster.close(epoch, "n0");
+            broadcaster.ack("n1", epoch);
+        }
+        // n1's parts still come in, they count but don't get rejected
+        assert_eq!(broadcaster.buffered, MAX_BUFFERED_TXNS + 4);
+        assert_eq!(rejected, 3 * MAX_BUFFERED_TXNS);
+        assert!(broadcaster.take_ready(4, "n0").is_empty());
+        for epoch in 0..4 {
+            broadcaster.push(epoch, "n2".to_string(), Vec::new());
+            broadcaster.ack("n2", epoch);
+        }
+        assert_eq!(broadcaster.take_ready(4, "n0").len(), 4);
+        assert_eq!(broadcaster.buffered, 0);
+        assert!(txn_error(broadcaster.buffered, 1).is_none());
+        assert!(matches!(
+            txn_error(0, MAX_TXN_OPS + 1),
+            Some(Pl::Error { code: 14, .. })
+        ));
+    }
+
+    // a `debug_assert`, release builds let it through
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "msg_id 7 reused")]
+    fn awaiting_a_reply_on_a_taken_msg_id_panics() {
+        let mut pending = HashMap::new();
+        await_reply(&mut pending, 7, "first");
+        await_reply(&mut pending, 8, "second");
+        await_reply(&mut pending, 7, "third");
+    }
+
+    // replies as they go on the wire, a rename in here is a protocol change so it should show up as one
+    #[test]
+    fn reply_wire_format_is_stable() {
+        let wire = |pl: Pl| {
+            serde_json::to_string(&Msg {
+                src: "n0".to_string(),
+                dst: "c1".to_string(),
+                body: Body {
+                    pl,
+                    msg_id: Some(2),
+                    in_reply_to: Some(1),
+                },
+            })
+            .unwrap()
+        };
+        fn one<T>(k: &str, v: T) -> HashMap<String, T> {
+            HashMap::from([(k.to_string(), v)])
+        }
+        let golden = [
+            (
+                wire(Pl::Error {
+                    code: 11,
+                    text: "busy".to_string(),
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"error","code":11,"text":"busy","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::InitOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"init_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::EchoOk {
+                    echo: "hi".to_string(),
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"echo_ok","echo":"hi","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::GenerateOk {
+                    id: "n0-1".to_string(),
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"generate_ok","id":"n0-1","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::BroadcastOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"broadcast_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::RemoveOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"remove_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::SubscribeOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"subscribe_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::Notify { msg: 5 }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"notify","message":5,"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::TopologyOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"topology_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::ReadOk {
+                    msgs: Some(HashSet::from([5])),
+                    value: None,
+                    behind: None,
+                    merged_at: None,
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"read_ok","messages":[5],"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::ReadOk {
+                    msgs: None,
+                    value: Some(-3),
+                    behind: Some(true),
+                    merged_at: Some(9),
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"read_ok","value":-3,"behind":true,"merged_at":9,"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::TraceOk {
+                    paths: vec![vec!["n1".to_string(), "n0".to_string()]],
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"trace_ok","paths":[["n1","n0"]],"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::GossipOk { id: 7 }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"gossip_ok","id":7,"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::AddOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"add_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::CasOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"cas_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::WriteOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"write_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::TxnOk {
+                    txn: vec![
+                        ('r', 1, None),
+                        (
+                            'w',
+                            2,
+                            Some(Val {
+                                n: 3,
+                                quoted: false,
+                            }),
+                        ),
+                    ],
+                    prior: Vec::new(),
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"txn_ok","txn":[["r",1,null],["w",2,3]],"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::TxnOk {
+                    txn: vec![('w', 2, Some(Val { n: 3, quoted: true }))],
+                    prior: vec![None],
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"txn_ok","txn":[["w",2,"3"]],"prior":[null],"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::BroadcastTxnOk { epoch: 4 }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"broadcast_txn_ok","epoch":4,"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::SendOk { offset: 8 }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"send_ok","offset":8,"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::SendManyOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"send_many_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::PollOk {
+                    msgs: one("k", vec![(0, 5), (1, 6)]),
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"poll_ok","msgs":{"k":[[0,5],[1,6]]},"msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::CommitOffsetsOk),
+                r#"{"src":"n0","dest":"c1","body":{"type":"commit_offsets_ok","msg_id":2,"in_reply_to":1}}"#,
+            ),
+            (
+                wire(Pl::ListCommittedOffsetsOk {
+                    offsets: one("k", 1),
+                }),
+                r#"{"src":"n0","dest":"c1","body":{"type":"list_committed_offsets_ok","offsets":{"k":1},"msg_id":2,"in_reply_to":1}}"#,
+            ),
+        ];
+        for (got, want) in golden {
+            assert_eq!(got, want);
+        }
+        let metrics = Metrics {
+            role: "leader".to_string(),
+            msgs_in: 1,
+            msgs_by_type: one("stats", 1),
+            messages: 0,
+            seen: HashMap::new(),
+            gossip_pending: 0,
+            kv_pending: 0,
+            relays: 0,
+            buffered_txns: 0,
+            store_version: 0,
+            unknown: 0,
+            queued: 0,
+            gossip_shed: 0,
+            relay_retries: 0,
+        };
+        assert_eq!(
+            wire(Pl::StatsOk {
+                metrics: Box::new(metrics)
+            }),
+            concat!(
+                r#"{"src":"n0","dest":"c1","body":{"type":"stats_ok","metrics":{"role":"leader","msgs_in":1,"#,
+                r#""msgs_by_type":{"stats":1},"messages":0,"seen":{},"gossip_pending":0,"kv_pending":0,"relays":0,"#,
+                r#""buffered_txns":0,"store_version":0,"unknown":0,"queued":0,"gossip_shed":0,"relay_retries":0},"#,
+                r#""msg_id":2,"in_reply_to":1}}"#
+            )
+        );
+        // the broadcast `read_ok`, written without going through `Pl`
+        let mut msgs = RoaringTreemap::new();
+        msgs.insert(70000);
+        msgs.insert(5);
+        let read_ok = |msgs, value| {
+            serde_json::to_string(&ReadOkRef {
+                src: "n0",
+                dst: "c1",
+                body: ReadOkBody {
+                    msgs,
+                    value,
+                    merged_at: None,
+                    msg_id: Some(2),
+                    in_reply_to: Some(1),
+                },
+            })
+            .unwrap()
+        };
+        assert_eq!(
+            read_ok(Some(&msgs), None),
+            r#"{"src":"n0","dest":"c1","body":{"type":"read_ok","messages":[5,70000],"msg_id":2,"in_reply_to":1}}"#
+        );
+        assert_eq!(
+            read_ok(None, Some(4)),
+            r#"{"src":"n0","dest":"c1","body":{"type":"read_ok","value":4,"msg_id":2,"in_reply_to":1}}"#
+        );
+        // and it reads back as the `Pl` one
+        let back: Msg = serde_json::from_str(&read_ok(Some(&msgs), Some(4))).unwrap();
+        assert!(matches!(
+            back.body.pl,
+            Pl::ReadOk { msgs: Some(x), value: Some(4), .. } if x == HashSet::from([5, 70000])
+        ));
+    }
+
+    // every node generating at once, a few restarts in between, no id comes out twice
+    #[test]
+    fn generated_ids_are_unique_across_nodes_and_restarts() {
+        let mut ids = HashSet::new();
+        for _restart in 0..3 {
+            let mut gens: Vec<Box<dyn IdGenerator>> =
+                (0..5).map(|_| Box::new(UuidV7) as _).collect();
+            for _ in 0..1000 {
+                for x in &mut gens {
+                    assert!(ids.insert(x.next_id()));
+                }
+            }
+        }
+        // `seeded-ids` repeats its ids on a restart on purpose, but no two nodes share one
+        let mut seeded = HashSet::new();
+        let mut gens: Vec<_> = (0..5).map(|node| SeededIds { node, seq: 0 }).collect();
+        for _ in 0..1000 {
+            for x in &mut gens {
+                assert!(seeded.insert(x.next_id()));
+            }
+        }
+        assert!(seeded.contains(&SeededIds { node: 0, seq: 0 }.next_id()));
+    }
+
+    // what one central gossip round sends after a broadcast, one gossip to each neighbour that
+    // hasn't acked the msg and none to the one that has
+    #[test]
+    fn a_gossip_round_goes_to_each_unsynced_neighbour_once() {
+        let ids = node_ids(4);
+        let mut seen: HashMap<_, _> = ids
+            .iter()
+            .map(|x| (x.clone(), RoaringTreemap::new()))
+            .collect();
+        let mut attempts = HashMap::new();
+        let mut messages = RoaringTreemap::new();
+        messages.insert(5);
+        seen.get_mut("n2").unwrap().insert(5);
+        let round: Vec<_> = ids[1..]
+            .iter()
+            .map(|x| (x.as_str(), unseen_by(x, &messages, &seen, &mut attempts)))
+            .filter(|(_, x)| !x.is_empty())
+            .collect();
+        assert_eq!(
+            round,
+            [("n1", HashSet::from([5])), ("n3", HashSet::from([5]))]
+        );
+    }
+
+    #[test]
+    fn gossip_deflates_for_peers_that_read_it_and_inflates_back() {
+        let msgs: HashSet<usize> = (0..COMPRESS_MIN_MSGS * 2).map(|x| x * 7).collect();
+        let wire =
+            |pl: Pl| -> Pl { serde_json::from_str(&serde_json::to_string(&pl).unwrap()).unwrap() };
+        let Pl::Gossip {
+            msgs: plain,
+            deflated,
+            ..
+        } = wire(gossip_pl(msgs.clone(), None, true, Vec::new()).unwrap())
+        else {
+            panic!("not a gossip");
+        };
+        if cfg!(feature = "compress") {
+            assert!(plain.is_empty());
+            assert_eq!(inflate(&deflated.unwrap()).unwrap(), msgs);
+        } else {
+            assert_eq!((plain, deflated), (msgs.clone(), None));
+        }
+        // a peer that didn't say `deflate` in its `rejoin`, or too few msgs to be worth it
+        let small: HashSet<usize> = (0..COMPRESS_MIN_MSGS - 1).collect();
+        for (msgs, deflate) in [(msgs, false), (small, true)] {
+            assert!(matches!(
+                gossip_pl(msgs.clone(), None, deflate, Vec::new()).unwrap(),
+                Pl::Gossip { msgs: x, deflated: None, .. } if x == msgs
+            ));
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_and_oversized_ones_are_refused() {
+        let msg = client_req("c1", 3);
+        let mut out = Vec::new();
+        write_out(&msg, &mut out).unwrap();
+        write_out(&msg, &mut out).unwrap();
+        let json = serde_json::to_string(&msg).unwrap();
+        if cfg!(feature = "framed") {
+            let mut input = io::Cursor::new(out);
+            for _ in 0..2 {
+                let back = Msg::read_frame(&mut input).unwrap().unwrap();
+                assert_eq!(serde_json::to_string(&back).unwrap(), json);
+            }
+            assert!(Msg::read_frame(&mut input).unwrap().is_none());
+        } else {
+            assert_eq!(String::from_utf8(out).unwrap(), format!("{json}\n{json}\n"));
+        }
+        let frame = |len: u32, body: &[u8]| io::Cursor::new([&len.to_be_bytes(), body].concat());
+        assert!(Msg::read_frame(&mut frame(json.len() as u32, json.as_bytes())).is_ok());
+        assert!(Msg::read_frame(&mut frame(MAX_FRAME as u32 + 1, b"")).is_err());
+        assert!(Msg::read_frame(&mut frame(u32::MAX, b"")).is_err());
+        // cut short after the length
+        assert!(Msg::read_frame(&mut frame(json.len() as u32, &json.as_bytes()[1..])).is_err());
+    }
+
+    #[test]
+    fn unacked_msgs_are_given_up_on_until_an_ack_or_rejoin() {
+        let mut seen = HashMap::from([("n1".to_string(), RoaringTreemap::new())]);
+        let mut attempts = HashMap::new();
+        let mut messages = RoaringTreemap::new();
+        messages.insert(5);
+        messages.insert(6);
+        for _ in 0..MAX_GOSSIP_ATTEMPTS {
+            assert_eq!(
+                unseen_by("n1", &messages, &seen, &mut attempts),
+                HashSet::from([5, 6])
+            );
+        }
+        assert!(unseen_by("n1", &messages, &seen, &mut attempts).is_empty());
+        // a late ack takes 5 off and drops its count, 6 stays given up on, a msg that came in after still goes
+        gossip_acked(
+            PendingGossip {
+                dst: "n1".to_string(),
+                msgs: HashSet::from([5]),
+                sent: Instant::now(),
+            },
+            &mut seen,
+            &mut attempts,
+        );
+        messages.insert(7);
+        assert_eq!(
+            unseen_by("n1", &messages, &seen, &mut attempts),
+            HashSet::from([7])
+        );
+        assert_eq!(attempts["n1"].get(&5), None);
+        // the node restarted, everything goes out again with a fresh count
+        reset_seen_for(&mut seen, &mut attempts, "n1");
+        assert_eq!(
+            unseen_by("n1", &messages, &seen, &mut attempts),
+            HashSet::from([5, 6, 7])
+        );
+        assert_eq!(attempts["n1"][&6], 1);
+    }
+
+    // half of every gossip and every ack lost, each node losing its own pattern like `lossy` does,
+    // the msgs still get everywhere since what isn't acked goes out again next round
+    #[test]
+    fn gossip_converges_with_half_of_it_lost() {
+        let ids = node_ids(3);
+        let mut messages: Vec<RoaringTreemap> = ids.iter().map(|_| RoaringTreemap::new()).collect();
+        let mut seen: Vec<HashMap<_, _>> = ids
+            .iter()
+            .map(|_| {
+                ids.iter()
+                    .map(|x| (x.clone(), RoaringTreemap::new()))
+                    .collect()
+            })
+            .collect();
+        let mut attempts: Vec<_> = ids.iter().map(|_| HashMap::new()).collect();
+        let mut rngs: Vec<_> = (0..ids.len() as u64).map(loss_seed).collect();
+        for (i, msg) in [(0, 1), (1, 2), (2, 3), (0, 4)] {
+            messages[i].insert(msg);
+        }
+        let all: RoaringTreemap = (1..=4).collect();
+        let mut dropped = 0;
+        let mut rounds = 0;
+        while messages.iter().any(|x| *x != all) {
+            rounds += 1;
+            assert!(rounds < 50, "not converged after {rounds} rounds");
+            for from in 0..ids.len() {
+                for to in (0..ids.len()).filter(|x| *x != from) {
+                    let msgs =
+                        unseen_by(&ids[to], &messages[from], &seen[from], &mut attempts[from]);
+                    if msgs.is_empty() {
+                        continue;
+                    }
+                    if drops(&mut rngs[from], LOSS_PERCENT) {
+                        dropped += 1;
+                        continue;
+                    }
+                    messages[to].extend(msgs.iter().map(|x| *x as u64));
+                    if drops(&mut rngs[to], LOSS_PERCENT) {
+                        dropped += 1;
+                        continue;
+                    }
+                    let gossip = PendingGossip {
+                        dst: ids[to].clone(),
+                        msgs,
+                        sent: Instant::now(),
+                    };
+                    gossip_acked(gossip, &mut seen[from], &mut attempts[from]);
+                }
+            }
+        }
+        assert!(dropped > 0);
+        // and no two nodes drop the same msgs
+        let pattern = |node| {
+            let mut rng = loss_seed(node);
+            (0..64)
+                .map(|_| drops(&mut rng, LOSS_PERCENT))
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(pattern(0), pattern(1));
+        assert_ne!(pattern(1), pattern(2));
+    }
+}